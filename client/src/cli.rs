@@ -1,7 +1,12 @@
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
 
 use anyhow::Context;
 use clap::{Parser, Subcommand, ValueEnum};
+use rrp::{
+    cidr::Cidr,
+    grpc::{HostProtocol, LoadBalancing},
+    proxy_protocol,
+};
 use tokio::fs;
 
 use crate::{proxy, server::ServerList};
@@ -47,7 +52,7 @@ pub enum Commands {
         server: String,
 
         /// The protocol
-        #[arg(value_enum, short, long)]
+        #[arg(value_enum, short, long, default_value = "tcp")]
         protocol: Protocol,
 
         /// The local port you want to expose
@@ -59,12 +64,187 @@ pub enum Commands {
         /// will use a random open port
         #[arg(short, long)]
         external: Option<u16>,
+
+        /// The server's ip address to expose the port on,
+        /// it needs to be allowed by the server
+        ///
+        /// the server's default address is used if not provided, tcp and udp only
+        #[arg(short, long, value_name = "IP", conflicts_with = "hostname")]
+        bind: Option<IpAddr>,
+
+        /// Only let visitors from these ip ranges through,
+        /// e.g. "203.0.113.0/24,2001:db8::/32"
+        ///
+        /// every visitor is let through if not provided, tcp only
+        #[arg(
+            long,
+            value_name = "CIDR",
+            value_delimiter = ',',
+            conflicts_with = "hostname"
+        )]
+        allow: Vec<Cidr>,
+
+        /// Drop visitors from these ip ranges,
+        /// even if they're in one of the allowed ranges
+        ///
+        /// tcp only
+        #[arg(
+            long,
+            value_name = "CIDR",
+            value_delimiter = ',',
+            conflicts_with = "hostname"
+        )]
+        deny: Vec<Cidr>,
+
+        /// The hostname visitors are routed by, required for http and tls
+        ///
+        /// a single label is used as a subdomain of the server's base domain
+        #[arg(long, required_if_eq_any([("protocol", "http"), ("protocol", "tls")]))]
+        hostname: Option<String>,
+
+        /// Require visitors to log in with these credentials
+        /// before the server lets them through, http only
+        #[arg(long, value_name = "USER:PASSWORD", value_parser = parse_basic_auth)]
+        basic_auth: Option<String>,
+
+        /// Require visitors to present this bearer token
+        /// before the server lets them through, http only
+        ///
+        /// visitors may present either one if both are required
+        #[arg(long, value_name = "TOKEN")]
+        bearer_token: Option<String>,
+
+        /// Share the external port (or hostname) with the other clients
+        /// that expose it with the same strategy, and spread the visitors across them
+        ///
+        /// the clients need to use the same credentials, or ones that the server
+        /// puts in the same load balancing group, tcp, http and tls only
+        #[arg(long, value_name = "STRATEGY")]
+        load_balance: Option<LoadBalance>,
+
+        /// Send a PROXY protocol header with the visitor's address
+        /// to the local server ahead of every connection
+        ///
+        /// the local server needs to expect the header, not supported for udp
+        #[arg(long, value_name = "VERSION")]
+        proxy_protocol: Option<ProxyProtocol>,
+
+        /// Expose the port as a private service under this name,
+        /// instead of on a public port
+        ///
+        /// only the clients that the server allows can visit it, tcp only
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["external", "bind", "allow", "deny", "hostname", "load_balance"])]
+        private: Option<String>,
+    },
+
+    // Visit another client's private service through the server
+    Visit {
+        /// The server's identifier the service is exposed on
+        #[arg(short, long)]
+        server: String,
+
+        /// The name of the private service
+        name: String,
+
+        /// The local port the service is made reachable on
+        #[arg(short, long)]
+        local: u16,
+    },
+
+    // Forward a local port to a destination that the server connects to
+    Forward {
+        /// The server's identifier through which you
+        /// want to forward the port
+        #[arg(short, long)]
+        server: String,
+
+        /// The local port you want to listen on
+        #[arg(short, long)]
+        local: u16,
+
+        /// The destination the server connects to,
+        /// it needs to be allowed by the server
+        #[arg(short, long, value_name = "host:port", value_parser = parse_destination)]
+        remote: (String, u16),
+    },
+
+    // Run a local SOCKS5 proxy that connects through the server
+    Socks {
+        /// The server's identifier through which you
+        /// want to connect
+        #[arg(short, long)]
+        server: String,
+
+        /// The local port the proxy listens on
+        #[arg(long, value_name = "PORT")]
+        listen: u16,
     },
 }
 
+// Makes sure basic auth credentials are in the form of "user:password"
+fn parse_basic_auth(credentials: &str) -> Result<String, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(credentials.to_string()),
+        _ => Err("expected credentials in the form of user:password".to_string()),
+    }
+}
+
+// Parses a "host:port" destination, ipv6 addresses can be bracketed
+fn parse_destination(destination: &str) -> Result<(String, u16), String> {
+    let (host, port) = destination
+        .rsplit_once(':')
+        .ok_or_else(|| "expected a destination in the form of host:port".to_string())?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid port number: {}", port))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+
+    Ok((host.to_string(), port))
+}
+
 #[derive(ValueEnum, Clone)]
 pub enum Protocol {
     Tcp,
+    Udp,
+    Http,
+    /// Tls passthrough, routed by the server name
+    Tls,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum LoadBalance {
+    RoundRobin,
+    LeastConnections,
+    /// Visitors from the same address keep going to the same client
+    SourceIp,
+}
+
+impl From<LoadBalance> for LoadBalancing {
+    fn from(strategy: LoadBalance) -> Self {
+        match strategy {
+            LoadBalance::RoundRobin => LoadBalancing::RoundRobin,
+            LoadBalance::LeastConnections => LoadBalancing::LeastConnections,
+            LoadBalance::SourceIp => LoadBalancing::SourceIp,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+impl From<ProxyProtocol> for proxy_protocol::Version {
+    fn from(version: ProxyProtocol) -> Self {
+        match version {
+            ProxyProtocol::V1 => proxy_protocol::Version::V1,
+            ProxyProtocol::V2 => proxy_protocol::Version::V2,
+        }
+    }
 }
 
 pub async fn run() -> anyhow::Result<()> {
@@ -104,15 +284,121 @@ pub async fn run() -> anyhow::Result<()> {
             protocol,
             local,
             external,
+            bind,
+            allow,
+            deny,
+            hostname,
+            basic_auth,
+            bearer_token,
+            load_balance,
+            proxy_protocol,
+            private,
         } => {
             let server = servers.get_server(&server).with_context(|| {
                 format!("can not find a server with \"{}\" as identifier", server)
             })?;
+            let proxy_protocol = proxy_protocol.map(proxy_protocol::Version::from);
+            let load_balancing = load_balance.map(LoadBalancing::from);
+
+            if private.is_some() && !matches!(protocol, Protocol::Tcp) {
+                anyhow::bail!("private services only support tcp");
+            }
+            let host_options = proxy::host::BindOptions {
+                auth: proxy::host::EdgeAuth {
+                    basic: basic_auth,
+                    bearer: bearer_token,
+                },
+                load_balancing,
+            };
+            if host_options.auth.is_required() && !matches!(protocol, Protocol::Http) {
+                anyhow::bail!("visitor credentials are only supported for http");
+            }
 
             match protocol {
-                Protocol::Tcp => proxy::tcp::expose_port(server, local, external).await?,
+                Protocol::Tcp => match private {
+                    Some(name) => {
+                        proxy::private::expose_service(server, local, name, proxy_protocol).await?
+                    }
+                    None => {
+                        if load_balancing.is_some() && external.is_none() {
+                            anyhow::bail!("load balanced ports need an external port to share");
+                        }
+                        let options = proxy::tcp::BindOptions {
+                            address: bind,
+                            filter: proxy::tcp::VisitorFilter { allow, deny },
+                            load_balancing,
+                        };
+                        proxy::tcp::expose_port(server, local, external, options, proxy_protocol)
+                            .await?
+                    }
+                },
+                Protocol::Udp => {
+                    if proxy_protocol.is_some() {
+                        anyhow::bail!("the PROXY protocol is not supported for udp");
+                    }
+                    if !allow.is_empty() || !deny.is_empty() {
+                        anyhow::bail!("visitor filters are not supported for udp");
+                    }
+                    if load_balancing.is_some() {
+                        anyhow::bail!("load balancing is not supported for udp");
+                    }
+                    proxy::udp::expose_port(server, local, external, bind).await?
+                }
+                Protocol::Http => {
+                    let hostname = hostname.expect("clap requires a hostname for http");
+                    proxy::host::expose_hostname(
+                        server,
+                        HostProtocol::Http,
+                        local,
+                        hostname,
+                        host_options,
+                        proxy_protocol,
+                    )
+                    .await?
+                }
+                Protocol::Tls => {
+                    let hostname = hostname.expect("clap requires a hostname for tls");
+                    proxy::host::expose_hostname(
+                        server,
+                        HostProtocol::Tls,
+                        local,
+                        hostname,
+                        host_options,
+                        proxy_protocol,
+                    )
+                    .await?
+                }
             }
         }
+        Commands::Visit {
+            server,
+            name,
+            local,
+        } => {
+            let server = servers.get_server(&server).with_context(|| {
+                format!("can not find a server with \"{}\" as identifier", server)
+            })?;
+
+            proxy::private::visit_service(server, name, local).await?
+        }
+        Commands::Forward {
+            server,
+            local,
+            remote: (host, port),
+        } => {
+            let server = servers.get_server(&server).with_context(|| {
+                format!("can not find a server with \"{}\" as identifier", server)
+            })?;
+
+            proxy::forward::forward_port(server, local, host, port).await?
+        }
+        Commands::Socks { server, listen } => {
+            let server = servers.get_server(&server).with_context(|| {
+                format!("can not find a server with \"{}\" as identifier", server)
+            })?;
+
+            proxy::socks::serve(server, listen).await?
+        }
     };
 
     Ok(())
@@ -1,32 +1,162 @@
 pub mod tcp {
-    use std::net::Ipv4Addr;
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
 
-    use crate::server::Server;
+    use crate::server::{AuthenticatedChannel, Server};
     use anyhow::Context;
-    use rrp::grpc::{
-        reverse_proxy_client::ReverseProxyClient, tcp_accept_request, tcp_bind_response, Packet,
-        TcpAcceptRequest, TcpAcceptRequestMetadata, TcpBindRequest,
+    use rrp::handshake::capabilities;
+    use rrp::{
+        cidr::Cidr,
+        grpc::{
+            reverse_proxy_client::ReverseProxyClient, tcp_accept_request, tcp_accept_response,
+            tcp_bind_request, tcp_bind_response, ConnectionInfo, Frame, LoadBalancing,
+            TcpAcceptRequest, TcpAcceptRequestMetadata, TcpAcceptResponse, TcpBindRequest,
+            TcpBindRequestMetadata,
+        },
+        proxy_protocol,
     };
-    use tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
-        net::TcpStream,
+    use tokio::{io::AsyncWriteExt, net::TcpStream, sync::mpsc};
+    use tokio_stream::StreamExt;
+
+    use super::{
+        heartbeat::{bind_requests, Liveness},
+        reconnect::Reconnect,
+        relay::relay,
     };
 
+    // The amount of heartbeat replies that we'll buffer on a bind stream
+    pub(super) const HEARTBEAT_BACK_PRESSURE: usize = 4;
+
     // The amount of packet's from the local server to the proxy
     // that we'll buffer blocking the local server
     const LOCAL_SERVER_PACKET_BACK_PRESSURE: usize = 10;
 
+    // A visitor's connection, as reported by the server
+    #[derive(Debug, Clone)]
+    pub struct Visitor {
+        pub remote_addr: SocketAddr,
+        // The server's address the visitor has connected to
+        pub local_addr: SocketAddr,
+        pub accepted_at: SystemTime,
+    }
+
+    impl TryFrom<ConnectionInfo> for Visitor {
+        type Error = anyhow::Error;
+
+        fn try_from(info: ConnectionInfo) -> anyhow::Result<Self> {
+            Ok(Self {
+                remote_addr: info
+                    .remote_addr
+                    .parse()
+                    .context("invalid visitor address")?,
+                local_addr: info
+                    .local_addr
+                    .parse()
+                    .context("invalid listening address")?,
+                accepted_at: UNIX_EPOCH + Duration::from_millis(info.accepted_at),
+            })
+        }
+    }
+
+    // The visitors the server lets through to the binding,
+    // the others are dropped by the server before we ever hear about them
+    #[derive(Debug, Default, Clone)]
+    pub struct VisitorFilter {
+        pub allow: Vec<Cidr>,
+        pub deny: Vec<Cidr>,
+    }
+
+    impl VisitorFilter {
+        fn is_empty(&self) -> bool {
+            self.allow.is_empty() && self.deny.is_empty()
+        }
+    }
+
+    // How the server binds the port
+    #[derive(Debug, Default, Clone)]
+    pub struct BindOptions {
+        // The server's address to bind the port on, the server's default one if not present
+        pub address: Option<IpAddr>,
+        pub filter: VisitorFilter,
+        // Shares the port with the client's other bindings of it,
+        // the port is exclusive to this binding if not present
+        pub load_balancing: Option<LoadBalancing>,
+    }
+
+    impl BindOptions {
+        // The features the server needs to support to honor the options,
+        // a server that doesn't know about them would silently ignore them
+        fn capabilities(&self) -> Vec<&'static str> {
+            let mut required = vec![capabilities::TCP];
+            if self.address.is_some() {
+                required.push(capabilities::BIND_ADDRESS);
+            }
+            if !self.filter.is_empty() {
+                required.push(capabilities::VISITOR_FILTERS);
+            }
+            if self.load_balancing.is_some() {
+                required.push(capabilities::LOAD_BALANCING);
+            }
+
+            required
+        }
+    }
+
     pub async fn expose_port(
         server: &Server,
         local_port: u16,
         external_port: Option<u16>,
+        options: BindOptions,
+        proxy_protocol: Option<proxy_protocol::Version>,
     ) -> anyhow::Result<()> {
-        let mut client = ReverseProxyClient::new(server.open_grpc_channel().await?);
+        // a lost binding is restored on the port it was assigned, even if the OS chose it
+        let mut external_port = external_port;
+        let mut reconnect = Reconnect::default();
+        loop {
+            let result = bind_port(
+                server,
+                local_port,
+                &mut external_port,
+                &options,
+                proxy_protocol,
+                &mut reconnect,
+            )
+            .await;
+            reconnect.lost(result).await?;
+        }
+    }
+
+    // Binds a port and accepts its connections until the binding is lost
+    async fn bind_port(
+        server: &Server,
+        local_port: u16,
+        external_port: &mut Option<u16>,
+        options: &BindOptions,
+        proxy_protocol: Option<proxy_protocol::Version>,
+        reconnect: &mut Reconnect,
+    ) -> anyhow::Result<()> {
+        let mut client = server.open_client(&options.capabilities()).await?;
+
+        let (requests, requests_stream) = bind_requests(
+            TcpBindRequest {
+                request: Some(tcp_bind_request::Request::Metadata(
+                    TcpBindRequestMetadata {
+                        port: external_port.map(|port| port as i32),
+                        bind_address: options.address.map(|ip| ip.to_string()),
+                        allow_visitors: options.filter.allow.iter().map(Cidr::to_string).collect(),
+                        deny_visitors: options.filter.deny.iter().map(Cidr::to_string).collect(),
+                        load_balancing: options.load_balancing.map(|strategy| strategy as i32),
+                    },
+                )),
+            },
+            HEARTBEAT_BACK_PRESSURE,
+        );
+        let mut liveness = Liveness::new(requests);
 
         let mut connections_stream = client
-            .bind_tcp(TcpBindRequest {
-                port: external_port.map(|port| port as i32),
-            })
+            .bind_tcp(requests_stream)
             .await
             .context("failed to expose the local port!")?
             .into_inner();
@@ -39,107 +169,1254 @@ pub mod tcp {
                 tcp_bind_response::Response::Metadata(md) => Some(md),
                 _ => None,
             })
-            .expect("the first message from the server should always contain metadata");
+            .context("the first message from the server should always contain metadata")?;
 
-        let external_port: u16 = metadata.port.try_into().unwrap();
-        println!("Reverse proxy listening on port: {}", external_port);
         // we can trust the server to return a valid port number
+        let port: u16 = metadata.port.try_into().unwrap();
+        *external_port = Some(port);
+        reconnect.established();
+        println!(
+            "Reverse proxy listening on port: {} (bound on {})",
+            port, metadata.bind_address
+        );
 
-        while let Some(message) = connections_stream.message().await? {
-            if matches!(
-                message.response,
-                Some(tcp_bind_response::Response::Connection(_))
-            ) {
-                // The proxy received a new connection, we need to accept it on the client side
-                let server = server.clone();
-                tokio::spawn(async move {
-                    if let Err(reason) =
-                        accept_connection(server.clone(), local_port, external_port).await
-                    {
-                        eprintln!("A client connection was terminated: {}", reason);
-                    }
-                });
+        while let Some(message) = liveness.next(&mut connections_stream).await? {
+            match message.response {
+                Some(tcp_bind_response::Response::Connection(connection)) => {
+                    // The proxy received a new connection, we need to accept it on the client side.
+                    // the accept stream is multiplexed over the same channel as the bind stream
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Err(reason) = accept_connection(
+                            client,
+                            local_port,
+                            connection.connection_id,
+                            proxy_protocol,
+                        )
+                        .await
+                        {
+                            eprintln!("A client connection was terminated: {}", reason);
+                        }
+                    });
+                }
+                Some(tcp_bind_response::Response::Heartbeat(heartbeat)) => {
+                    liveness.reply(heartbeat).await?;
+                }
+                _ => {}
             }
         }
 
         Ok(())
     }
 
-    async fn accept_connection(
-        server: Server,
+    // Accepts a pending connection and connects it to the local server
+    //
+    // the connection is claimed by the id the server announced it with,
+    // a PROXY protocol header is sent to the local server if a version is provided
+    pub(super) async fn accept_connection(
+        mut client: ReverseProxyClient<AuthenticatedChannel>,
         local_port: u16,
-        external_port: u16,
+        connection_id: u64,
+        proxy_protocol: Option<proxy_protocol::Version>,
     ) -> anyhow::Result<()> {
-        // open a seperate, new, connection to the proxy
-        let mut client = ReverseProxyClient::new(server.open_grpc_channel().await?);
         // open a new connection to the local server
         let mut local_server = TcpStream::connect((Ipv4Addr::LOCALHOST, local_port))
             .await
             .with_context(|| format!("failed to connect to the local server at: {}", local_port))?;
-        let (mut reader, mut writer) = local_server.split();
 
-        // a channel for the messages we send to the client through the proxy
-        let (rx, mut tx) = tokio::sync::mpsc::channel::<Vec<u8>>(LOCAL_SERVER_PACKET_BACK_PRESSURE);
+        // a channel for the frames we send to the client through the proxy
+        let (to_proxy, mut frames) = mpsc::channel::<Frame>(LOCAL_SERVER_PACKET_BACK_PRESSURE);
 
         // create a stream from the local server's output
         let local_server_stream = async_stream::stream! {
-            // we need to provide the proxy with the external port we're accepting from
+            // we need to tell the proxy which of the pending connections we're accepting
             yield TcpAcceptRequest {
-                request: Some(tcp_accept_request::Request::Metadata(
-                    TcpAcceptRequestMetadata {
-                        port: external_port as i32,
-                    },
-                )),
+                request: Some(tcp_accept_request::Request::Metadata(TcpAcceptRequestMetadata {
+                    connection_id,
+                })),
             };
 
-            while let Some(packet) = tx.recv().await {
-                yield TcpAcceptRequest {
-                    request: Some(tcp_accept_request::Request::Packet(Packet { data: packet })),
-                }
+            while let Some(frame) = frames.recv().await {
+                yield frame.into();
             }
         };
 
-        // a future that reads from the local server and feeds the stream
-        let from_local_server = async move {
-            let mut data = vec![0u8; 4096];
-            loop {
-                let rcount = reader.read(&mut data).await?;
-                if rcount == 0 {
-                    break;
+        // accept and connect a new client to the local server through the reverse proxy
+        let mut client_stream = client
+            .accept_tcp_connection(local_server_stream)
+            .await?
+            .into_inner();
+
+        let visitor: Visitor = client_stream
+            .message()
+            .await?
+            .and_then(|msg| msg.response)
+            .and_then(|msg| match msg {
+                tcp_accept_response::Response::Metadata(md) => md.info,
+                _ => None,
+            })
+            .context("the first message from the server should always contain metadata")?
+            .try_into()?;
+        println!(
+            "Accepted connection #{} from {} on {} (pending for {:?})",
+            connection_id,
+            visitor.remote_addr,
+            visitor.local_addr,
+            visitor.accepted_at.elapsed().unwrap_or_default()
+        );
+
+        // the header needs to arrive before any of the visitor's data
+        if let Some(version) = proxy_protocol {
+            let header = proxy_protocol::encode(version, visitor.remote_addr, visitor.local_addr);
+            local_server
+                .write_all(&header)
+                .await
+                .context("failed to send the PROXY protocol header to the local server")?;
+        }
+
+        #[allow(clippy::result_large_err)]
+        let frames = client_stream.map(|msg| msg.map(TcpAcceptResponse::into_frame));
+        relay(local_server, to_proxy, frames).await
+    }
+}
+
+pub mod relay {
+    use std::time::Duration;
+
+    use anyhow::Context;
+    use rrp::grpc::{control, Control, Frame, Packet};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::mpsc,
+    };
+    use tokio_stream::{Stream, StreamExt};
+    use tonic::Status;
+
+    // Connects a local connection with its end on the server
+    //
+    // the local connection's frames are sent to the server through the channel,
+    // the server's frames are the messages it sends after the metadata, None if a message doesn't carry one.
+    // the server ends its stream once it's done with the connection
+    pub async fn relay<S>(
+        local: TcpStream,
+        to_proxy: mpsc::Sender<Frame>,
+        mut frames: S,
+    ) -> anyhow::Result<()>
+    where
+        S: Stream<Item = Result<Option<Frame>, Status>> + Unpin,
+    {
+        let (mut reader, mut writer) = local.into_split();
+
+        // a future that reads from the local connection and feeds the stream
+        let from_local = {
+            let to_proxy = to_proxy.clone();
+            async move {
+                let mut data = vec![0u8; 4096];
+                loop {
+                    let (frame, fin) = match reader.read(&mut data).await {
+                        // the local connection has closed its writing part
+                        Ok(0) => (Control::fin().into(), true),
+                        Ok(rcount) => {
+                            let packet = Packet {
+                                data: data[..rcount].to_vec(),
+                            };
+                            (packet.into(), false)
+                        }
+                        Err(err) => {
+                            let _ = to_proxy.send(Control::from_io_error(&err).into()).await;
+                            return Err(err).context("failed to read from the local socket");
+                        }
+                    };
+
+                    // the proxy is gone if the stream was dropped, it'll let us know why
+                    if to_proxy.send(frame).await.is_err() || fin {
+                        break;
+                    }
                 }
 
-                rx.send(data[..rcount].to_vec()).await.unwrap();
+                Ok::<_, anyhow::Error>(())
+            }
+        };
+
+        let from_proxy = async move {
+            while let Some(frame) = frames.next().await {
+                let result = match frame? {
+                    Some(Frame::Packet(packet)) => writer.write_all(&packet.data).await,
+                    Some(Frame::Control(control)) => match control.kind() {
+                        // the server's end has closed its writing part
+                        control::Kind::Fin => writer.shutdown().await,
+                        control::Kind::Rst => {
+                            let _ = writer.as_ref().set_linger(Some(Duration::ZERO));
+                            anyhow::bail!("the connection was reset on the server's end");
+                        }
+                        control::Kind::Error => {
+                            let _ = writer.as_ref().set_linger(Some(Duration::ZERO));
+                            anyhow::bail!(
+                                "the server failed to relay the connection: {}",
+                                control.reason
+                            );
+                        }
+                    },
+                    None => anyhow::bail!(
+                        "all messages, except the first one, should contain either a packet or a control frame"
+                    ),
+                };
+
+                if let Err(err) = result {
+                    let _ = to_proxy.send(Control::from_io_error(&err).into()).await;
+                    return Err(err).context("failed to write to the local socket");
+                }
             }
 
             Ok::<_, anyhow::Error>(())
         };
 
-        // accept and connect a new client to the local server through the reverse proxy
-        let mut client_stream = client
-            .accept_tcp_connection(local_server_stream)
+        // the connection is done once the server ends the stream,
+        // whatever the local connection is still up to
+        let from_local = tokio::spawn(from_local);
+        let result = from_proxy.await;
+        from_local.abort();
+
+        // a local failure is the reason the server has ended the stream
+        if let Ok(Err(err)) = from_local.await {
+            return Err(err);
+        }
+        result
+    }
+}
+
+pub mod heartbeat {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use rrp::grpc::Heartbeat;
+    use tokio::sync::mpsc;
+    use tokio_stream::Stream;
+    use tonic::Streaming;
+
+    // Opens the request stream of a bind rpc,
+    // the metadata is sent first, followed by whatever is sent through the channel
+    pub fn bind_requests<T: Send + 'static>(
+        metadata: T,
+        back_pressure: usize,
+    ) -> (mpsc::Sender<T>, impl Stream<Item = T>) {
+        let (tx, mut rx) = mpsc::channel(back_pressure);
+        let stream = async_stream::stream! {
+            yield metadata;
+
+            while let Some(request) = rx.recv().await {
+                yield request;
+            }
+        };
+
+        (tx, stream)
+    }
+
+    // Watches the server's liveness on a bind stream
+    //
+    // every heartbeat the server sends is echoed back, and the server is considered dead
+    // once it has been silent for longer than its last heartbeat said it would be
+    pub struct Liveness<T> {
+        replies: mpsc::Sender<T>,
+        timeout: Option<Duration>,
+    }
+
+    impl<T: From<Heartbeat>> Liveness<T> {
+        pub fn new(replies: mpsc::Sender<T>) -> Self {
+            Self {
+                replies,
+                timeout: None,
+            }
+        }
+
+        // Waits for the next message of the bind stream
+        pub async fn next<M>(&self, stream: &mut Streaming<M>) -> anyhow::Result<Option<M>> {
+            let message = match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.message())
+                    .await
+                    .map_err(|_| anyhow!("the server hasn't responded for {:?}", timeout))?,
+                None => stream.message().await,
+            };
+
+            Ok(message?)
+        }
+
+        // Echoes a heartbeat back to the server
+        pub async fn reply(&mut self, heartbeat: Heartbeat) -> anyhow::Result<()> {
+            self.timeout = Some(Duration::from_millis(heartbeat.timeout));
+            self.replies
+                .send(heartbeat.into())
+                .await
+                .map_err(|_| anyhow!("the bind stream was closed"))
+        }
+    }
+}
+
+pub mod reconnect {
+    use std::time::Duration;
+
+    use rand::Rng;
+
+    // The delay before the first attempt to restore a lost binding
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+    // The longest we'll wait between two attempts
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    // Restores lost bindings, waiting exponentially longer between failed attempts
+    //
+    // a binding that was never established isn't retried,
+    // since its error is most likely something that needs to be fixed by hand
+    pub struct Reconnect {
+        backoff: Duration,
+        established: bool,
+        reconnecting: bool,
+    }
+
+    impl Default for Reconnect {
+        fn default() -> Self {
+            Self {
+                backoff: INITIAL_BACKOFF,
+                established: false,
+                reconnecting: false,
+            }
+        }
+    }
+
+    impl Reconnect {
+        // Marks the binding as established, should be called once the server has sent its metadata
+        pub fn established(&mut self) {
+            if self.reconnecting {
+                println!("Reconnected to the server");
+            }
+
+            self.backoff = INITIAL_BACKOFF;
+            self.established = true;
+            self.reconnecting = false;
+        }
+
+        // Waits before the next attempt to restore the binding,
+        // fails if the binding was never established
+        pub async fn lost(&mut self, result: anyhow::Result<()>) -> anyhow::Result<()> {
+            let reason = match result {
+                Ok(()) => anyhow::anyhow!("the server closed the binding"),
+                Err(err) => err,
+            };
+            if !self.established {
+                return Err(reason);
+            }
+
+            // spread the attempts, so clients that lost the server together don't all retry together
+            let delay = self
+                .backoff
+                .mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+            match self.reconnecting {
+                true => eprintln!("Failed to reconnect: {}, retrying in {:.1?}", reason, delay),
+                false => eprintln!(
+                    "Lost the connection to the server: {}, reconnecting in {:.1?}",
+                    reason, delay
+                ),
+            }
+
+            tokio::time::sleep(delay).await;
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            self.reconnecting = true;
+            Ok(())
+        }
+    }
+}
+
+pub mod forward {
+    use crate::server::{AuthenticatedChannel, Server};
+    use anyhow::Context;
+    use rrp::grpc::{
+        reverse_proxy_client::ReverseProxyClient, tcp_connect_request, tcp_connect_response, Frame,
+        TcpConnectRequest, TcpConnectRequestMetadata, TcpConnectResponse,
+    };
+    use rrp::handshake::capabilities;
+    use std::net::Ipv4Addr;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_stream::StreamExt;
+    use tonic::{Status, Streaming};
+
+    use super::relay::relay;
+
+    // The amount of frames from a local connection to the proxy
+    // that we'll buffer blocking the local connection
+    const LOCAL_CONNECTION_PACKET_BACK_PRESSURE: usize = 10;
+
+    // A connection the server has opened to a destination on our behalf
+    pub struct Tunnel {
+        // The address the server has connected to
+        pub remote_addr: String,
+        to_proxy: mpsc::Sender<Frame>,
+        frames: Streaming<TcpConnectResponse>,
+    }
+
+    impl Tunnel {
+        // Has the server connect to the destination
+        pub async fn open(
+            mut client: ReverseProxyClient<AuthenticatedChannel>,
+            host: String,
+            port: u16,
+        ) -> Result<Self, Status> {
+            // a channel for the frames we send to the destination through the proxy
+            let (to_proxy, mut frames) =
+                mpsc::channel::<Frame>(LOCAL_CONNECTION_PACKET_BACK_PRESSURE);
+
+            let local_stream = async_stream::stream! {
+                // we need to tell the proxy where to connect to
+                yield TcpConnectRequest {
+                    request: Some(tcp_connect_request::Request::Metadata(TcpConnectRequestMetadata {
+                        host,
+                        port: port as i32,
+                    })),
+                };
+
+                while let Some(frame) = frames.recv().await {
+                    yield frame.into();
+                }
+            };
+
+            let mut remote_stream = client.connect_tcp(local_stream).await?.into_inner();
+
+            let remote_addr = remote_stream
+                .message()
+                .await?
+                .and_then(|msg| msg.response)
+                .and_then(|msg| match msg {
+                    tcp_connect_response::Response::Metadata(md) => Some(md.remote_addr),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    Status::internal(
+                        "the first message from the server should always contain metadata",
+                    )
+                })?;
+
+            Ok(Self {
+                remote_addr,
+                to_proxy,
+                frames: remote_stream,
+            })
+        }
+
+        // Relays a local connection through the tunnel, until either side is done with it
+        pub async fn relay(self, local: TcpStream) -> anyhow::Result<()> {
+            #[allow(clippy::result_large_err)]
+            let frames = self
+                .frames
+                .map(|msg| msg.map(TcpConnectResponse::into_frame));
+            relay(local, self.to_proxy, frames).await
+        }
+    }
+
+    // Forwards the connections to a local port to a remote destination,
+    // the server opens the connection to the destination on our behalf
+    pub async fn forward_port(
+        server: &Server,
+        local_port: u16,
+        host: String,
+        port: u16,
+    ) -> anyhow::Result<()> {
+        let client = server.open_client(&[capabilities::CONNECT]).await?;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, local_port))
+            .await
+            .with_context(|| format!("failed to listen on the local port: {}", local_port))?;
+        println!(
+            "Forwarding local port {} to {}:{} through the server",
+            local_port, host, port
+        );
+
+        loop {
+            let (conn, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("Failed to accept a local connection: {}", err);
+                    continue;
+                }
+            };
+
+            // every connection is just another stream on the same channel
+            let client = client.clone();
+            let host = host.clone();
+            tokio::spawn(async move {
+                let result = async {
+                    let tunnel = Tunnel::open(client, host, port).await?;
+                    println!(
+                        "Forwarding a connection from {} to {}",
+                        peer_addr, tunnel.remote_addr
+                    );
+
+                    tunnel.relay(conn).await
+                };
+
+                if let Err(reason) = result.await {
+                    eprintln!(
+                        "A forwarded connection from {} was terminated: {}",
+                        peer_addr, reason
+                    );
+                }
+            });
+        }
+    }
+}
+
+pub mod socks {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use crate::server::{AuthenticatedChannel, Server};
+    use anyhow::Context;
+    use rrp::grpc::reverse_proxy_client::ReverseProxyClient;
+    use rrp::handshake::capabilities;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tonic::Code;
+
+    use super::forward::Tunnel;
+
+    // see: https://www.rfc-editor.org/rfc/rfc1928
+    const VERSION: u8 = 5;
+    const METHOD_NO_AUTHENTICATION: u8 = 0;
+    const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+    const COMMAND_CONNECT: u8 = 1;
+    const ADDRESS_IPV4: u8 = 1;
+    const ADDRESS_DOMAIN_NAME: u8 = 3;
+    const ADDRESS_IPV6: u8 = 4;
+
+    const REPLY_SUCCEEDED: u8 = 0;
+    const REPLY_GENERAL_FAILURE: u8 = 1;
+    const REPLY_NOT_ALLOWED: u8 = 2;
+    const REPLY_HOST_UNREACHABLE: u8 = 4;
+    const REPLY_CONNECTION_REFUSED: u8 = 5;
+    const REPLY_TTL_EXPIRED: u8 = 6;
+    const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+    const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+    // Runs a local SOCKS5 server, the server connects to the requested destinations on our behalf
+    //
+    // only the CONNECT command is supported
+    pub async fn serve(server: &Server, listen_port: u16) -> anyhow::Result<()> {
+        let client = server.open_client(&[capabilities::CONNECT]).await?;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, listen_port))
+            .await
+            .with_context(|| format!("failed to listen on the local port: {}", listen_port))?;
+        println!("SOCKS5 proxy listening on port: {}", listen_port);
+
+        loop {
+            let (conn, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("Failed to accept a local connection: {}", err);
+                    continue;
+                }
+            };
+
+            // every connection is just another stream on the same channel
+            let client = client.clone();
+            tokio::spawn(async move {
+                if let Err(reason) = handle_connection(client, conn).await {
+                    eprintln!(
+                        "A SOCKS connection from {} was terminated: {}",
+                        peer_addr, reason
+                    );
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        client: ReverseProxyClient<AuthenticatedChannel>,
+        mut conn: TcpStream,
+    ) -> anyhow::Result<()> {
+        // the method negotiation
+        let version = conn.read_u8().await?;
+        anyhow::ensure!(version == VERSION, "unsupported SOCKS version: {}", version);
+        let mut methods = vec![0u8; conn.read_u8().await? as usize];
+        conn.read_exact(&mut methods).await?;
+        if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+            conn.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+            anyhow::bail!("the SOCKS client requires authentication");
+        }
+        conn.write_all(&[VERSION, METHOD_NO_AUTHENTICATION]).await?;
+
+        // the request
+        let mut header = [0u8; 4];
+        conn.read_exact(&mut header).await?;
+        let [_, command, _, address_type] = header;
+        let host = match address_type {
+            ADDRESS_IPV4 => {
+                let mut octets = [0u8; 4];
+                conn.read_exact(&mut octets).await?;
+                Ipv4Addr::from(octets).to_string()
+            }
+            ADDRESS_IPV6 => {
+                let mut octets = [0u8; 16];
+                conn.read_exact(&mut octets).await?;
+                Ipv6Addr::from(octets).to_string()
+            }
+            ADDRESS_DOMAIN_NAME => {
+                let mut name = vec![0u8; conn.read_u8().await? as usize];
+                conn.read_exact(&mut name).await?;
+                String::from_utf8(name).context("invalid domain name")?
+            }
+            _ => {
+                reply(&mut conn, REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+                anyhow::bail!("unsupported address type: {}", address_type);
+            }
+        };
+        let port = conn.read_u16().await?;
+        if command != COMMAND_CONNECT {
+            reply(&mut conn, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+            anyhow::bail!("unsupported SOCKS command: {}", command);
+        }
+
+        let tunnel = match Tunnel::open(client, host.clone(), port).await {
+            Ok(tunnel) => tunnel,
+            Err(status) => {
+                let code = match status.code() {
+                    Code::PermissionDenied => REPLY_NOT_ALLOWED,
+                    Code::NotFound => REPLY_HOST_UNREACHABLE,
+                    Code::Unavailable => REPLY_CONNECTION_REFUSED,
+                    Code::DeadlineExceeded => REPLY_TTL_EXPIRED,
+                    _ => REPLY_GENERAL_FAILURE,
+                };
+                reply(&mut conn, code, None).await?;
+                return Err(status.into());
+            }
+        };
+        println!(
+            "Forwarding a SOCKS connection to {}:{} ({})",
+            host, port, tunnel.remote_addr
+        );
+
+        reply(&mut conn, REPLY_SUCCEEDED, tunnel.remote_addr.parse().ok()).await?;
+        tunnel.relay(conn).await
+    }
+
+    // Replies to a request, the bound address is the destination's when it's known
+    async fn reply(
+        conn: &mut TcpStream,
+        code: u8,
+        bound_addr: Option<SocketAddr>,
+    ) -> std::io::Result<()> {
+        let bound_addr =
+            bound_addr.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+
+        let mut response = vec![VERSION, code, 0];
+        match bound_addr.ip() {
+            IpAddr::V4(ip) => {
+                response.push(ADDRESS_IPV4);
+                response.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                response.push(ADDRESS_IPV6);
+                response.extend_from_slice(&ip.octets());
+            }
+        }
+        response.extend_from_slice(&bound_addr.port().to_be_bytes());
+
+        conn.write_all(&response).await
+    }
+}
+
+pub mod private {
+    use std::net::Ipv4Addr;
+
+    use crate::server::{AuthenticatedChannel, Server};
+    use anyhow::Context;
+    use rrp::handshake::capabilities;
+    use rrp::{
+        grpc::{
+            private_bind_request, private_bind_response, private_visit_request,
+            private_visit_response, reverse_proxy_client::ReverseProxyClient, Frame,
+            PrivateBindRequest, PrivateBindRequestMetadata, PrivateVisitRequest,
+            PrivateVisitRequestMetadata, PrivateVisitResponse,
+        },
+        proxy_protocol,
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_stream::StreamExt;
+
+    use super::{
+        heartbeat::{bind_requests, Liveness},
+        reconnect::Reconnect,
+        relay::relay,
+        tcp::{accept_connection, HEARTBEAT_BACK_PRESSURE},
+    };
+
+    // The amount of frames from a local connection to the proxy
+    // that we'll buffer blocking the local connection
+    const LOCAL_CONNECTION_PACKET_BACK_PRESSURE: usize = 10;
+
+    // Exposes a local port as a private service, without a public port on the server
+    //
+    // only the clients we allow in the server's clients file can visit it
+    pub async fn expose_service(
+        server: &Server,
+        local_port: u16,
+        name: String,
+        proxy_protocol: Option<proxy_protocol::Version>,
+    ) -> anyhow::Result<()> {
+        let mut name = name;
+        let mut reconnect = Reconnect::default();
+        loop {
+            let result = bind_service(
+                server,
+                local_port,
+                &mut name,
+                proxy_protocol,
+                &mut reconnect,
+            )
+            .await;
+            reconnect.lost(result).await?;
+        }
+    }
+
+    // Registers the service and accepts its visitors until the registration is lost
+    async fn bind_service(
+        server: &Server,
+        local_port: u16,
+        name: &mut String,
+        proxy_protocol: Option<proxy_protocol::Version>,
+        reconnect: &mut Reconnect,
+    ) -> anyhow::Result<()> {
+        let mut client = server.open_client(&[capabilities::PRIVATE]).await?;
+
+        let (requests, requests_stream) = bind_requests(
+            PrivateBindRequest {
+                request: Some(private_bind_request::Request::Metadata(
+                    PrivateBindRequestMetadata { name: name.clone() },
+                )),
+            },
+            HEARTBEAT_BACK_PRESSURE,
+        );
+        let mut liveness = Liveness::new(requests);
+
+        let mut connections_stream = client
+            .bind_private(requests_stream)
+            .await
+            .context("failed to expose the local port!")?
+            .into_inner();
+
+        let metadata = connections_stream
+            .message()
             .await?
+            .and_then(|md| md.response)
+            .and_then(|md| match md {
+                private_bind_response::Response::Metadata(md) => Some(md),
+                _ => None,
+            })
+            .context("the first message from the server should always contain metadata")?;
+
+        *name = metadata.name;
+        reconnect.established();
+        println!("Exposing a private service named \"{}\"", name);
+
+        while let Some(message) = liveness.next(&mut connections_stream).await? {
+            match message.response {
+                Some(private_bind_response::Response::Connection(connection)) => {
+                    // Another client is visiting the service, we need to accept it on the client side
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Err(reason) = accept_connection(
+                            client,
+                            local_port,
+                            connection.connection_id,
+                            proxy_protocol,
+                        )
+                        .await
+                        {
+                            eprintln!("A client connection was terminated: {}", reason);
+                        }
+                    });
+                }
+                Some(private_bind_response::Response::Heartbeat(heartbeat)) => {
+                    liveness.reply(heartbeat).await?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Makes another client's private service reachable on a local port
+    pub async fn visit_service(
+        server: &Server,
+        name: String,
+        local_port: u16,
+    ) -> anyhow::Result<()> {
+        let client = server.open_client(&[capabilities::PRIVATE]).await?;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, local_port))
+            .await
+            .with_context(|| format!("failed to listen on the local port: {}", local_port))?;
+        println!(
+            "Private service \"{}\" is reachable on local port: {}",
+            name, local_port
+        );
+
+        loop {
+            let (conn, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("Failed to accept a local connection: {}", err);
+                    continue;
+                }
+            };
+
+            // every connection is just another stream on the same channel
+            let client = client.clone();
+            let name = name.clone();
+            tokio::spawn(async move {
+                if let Err(reason) = visit(client, conn, name).await {
+                    eprintln!(
+                        "A connection from {} to a private service was terminated: {}",
+                        peer_addr, reason
+                    );
+                }
+            });
+        }
+    }
+
+    async fn visit(
+        mut client: ReverseProxyClient<AuthenticatedChannel>,
+        local: TcpStream,
+        name: String,
+    ) -> anyhow::Result<()> {
+        // a channel for the frames we send to the service through the proxy
+        let (to_proxy, mut frames) = mpsc::channel::<Frame>(LOCAL_CONNECTION_PACKET_BACK_PRESSURE);
+
+        let local_stream = async_stream::stream! {
+            // we need to tell the proxy which service we're visiting
+            yield PrivateVisitRequest {
+                request: Some(private_visit_request::Request::Metadata(PrivateVisitRequestMetadata {
+                    name,
+                })),
+            };
+
+            while let Some(frame) = frames.recv().await {
+                yield frame.into();
+            }
+        };
+
+        let mut service_stream = client.visit_private(local_stream).await?.into_inner();
+
+        let owner = service_stream
+            .message()
+            .await?
+            .and_then(|msg| msg.response)
+            .and_then(|msg| match msg {
+                private_visit_response::Response::Metadata(md) => Some(md.owner),
+                _ => None,
+            })
+            .context("the first message from the server should always contain metadata")?;
+        println!(
+            "Visiting a private service of \"{}\" from {}",
+            owner,
+            local.peer_addr()?
+        );
+
+        #[allow(clippy::result_large_err)]
+        let frames = service_stream.map(|msg| msg.map(PrivateVisitResponse::into_frame));
+        relay(local, to_proxy, frames).await
+    }
+}
+
+pub mod host {
+    use crate::server::Server;
+    use anyhow::Context;
+    use rrp::handshake::capabilities;
+    use rrp::{
+        grpc::{
+            host_bind_request, host_bind_response, HostBindRequest, HostBindRequestMetadata,
+            HostProtocol, LoadBalancing,
+        },
+        proxy_protocol,
+    };
+
+    use super::{
+        heartbeat::{bind_requests, Liveness},
+        reconnect::Reconnect,
+        tcp::{accept_connection, HEARTBEAT_BACK_PRESSURE},
+    };
+
+    // The credentials the server requires from the visitors of an http binding,
+    // the visitors that don't present either one get a 401 from the server
+    #[derive(Debug, Default, Clone)]
+    pub struct EdgeAuth {
+        // "user:password"
+        pub basic: Option<String>,
+        pub bearer: Option<String>,
+    }
+
+    impl EdgeAuth {
+        pub fn is_required(&self) -> bool {
+            self.basic.is_some() || self.bearer.is_some()
+        }
+    }
+
+    // How the server binds the hostname
+    #[derive(Debug, Default, Clone)]
+    pub struct BindOptions {
+        pub auth: EdgeAuth,
+        // Shares the hostname with the client's other bindings of it,
+        // the hostname is exclusive to this binding if not present
+        pub load_balancing: Option<LoadBalancing>,
+    }
+
+    impl BindOptions {
+        // The features the server needs to support to honor the options,
+        // a server that doesn't know about them would silently ignore them
+        fn capabilities(&self, protocol: HostProtocol) -> Vec<&'static str> {
+            let mut required = vec![match protocol {
+                HostProtocol::Http => capabilities::HTTP,
+                HostProtocol::Tls => capabilities::TLS,
+            }];
+            if self.auth.is_required() {
+                required.push(capabilities::EDGE_AUTH);
+            }
+            if self.load_balancing.is_some() {
+                required.push(capabilities::LOAD_BALANCING);
+            }
+
+            required
+        }
+    }
+
+    // Exposes a local port under a hostname on one of the server's shared ports
+    pub async fn expose_hostname(
+        server: &Server,
+        protocol: HostProtocol,
+        local_port: u16,
+        hostname: String,
+        options: BindOptions,
+        proxy_protocol: Option<proxy_protocol::Version>,
+    ) -> anyhow::Result<()> {
+        let mut hostname = hostname;
+        let mut reconnect = Reconnect::default();
+        loop {
+            let result = bind_hostname(
+                server,
+                protocol,
+                local_port,
+                &mut hostname,
+                &options,
+                proxy_protocol,
+                &mut reconnect,
+            )
+            .await;
+            reconnect.lost(result).await?;
+        }
+    }
+
+    // Binds the hostname and accepts its connections until the binding is lost
+    async fn bind_hostname(
+        server: &Server,
+        protocol: HostProtocol,
+        local_port: u16,
+        hostname: &mut String,
+        options: &BindOptions,
+        proxy_protocol: Option<proxy_protocol::Version>,
+        reconnect: &mut Reconnect,
+    ) -> anyhow::Result<()> {
+        let mut client = server.open_client(&options.capabilities(protocol)).await?;
+
+        let (requests, requests_stream) = bind_requests(
+            HostBindRequest {
+                request: Some(host_bind_request::Request::Metadata(
+                    HostBindRequestMetadata {
+                        protocol: protocol as i32,
+                        hostname: hostname.clone(),
+                        basic_auth: options.auth.basic.clone(),
+                        bearer_token: options.auth.bearer.clone(),
+                        load_balancing: options.load_balancing.map(|strategy| strategy as i32),
+                    },
+                )),
+            },
+            HEARTBEAT_BACK_PRESSURE,
+        );
+        let mut liveness = Liveness::new(requests);
+
+        let mut connections_stream = client
+            .bind_host(requests_stream)
+            .await
+            .context("failed to expose the local port!")?
             .into_inner();
 
-        let from_client = async move {
-            while let Some(packet) = client_stream.message().await? {
-                if packet.data.is_empty() {
-                    writer.shutdown().await?;
-                    break;
+        let metadata = connections_stream
+            .message()
+            .await?
+            .and_then(|md| md.response)
+            .and_then(|md| match md {
+                host_bind_response::Response::Metadata(md) => Some(md),
+                _ => None,
+            })
+            .context("the first message from the server should always contain metadata")?;
+
+        // the hostname is fully qualified from now on
+        *hostname = metadata.hostname;
+        reconnect.established();
+        println!(
+            "Reverse proxy routing \"{}\" on port: {}",
+            hostname, metadata.port
+        );
+
+        while let Some(message) = liveness.next(&mut connections_stream).await? {
+            match message.response {
+                Some(host_bind_response::Response::Connection(connection)) => {
+                    // The proxy routed a new connection to us, we need to accept it on the client side
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Err(reason) = accept_connection(
+                            client,
+                            local_port,
+                            connection.connection_id,
+                            proxy_protocol,
+                        )
+                        .await
+                        {
+                            eprintln!("A client connection was terminated: {}", reason);
+                        }
+                    });
                 }
-                writer
-                    .write_all(&packet.data)
-                    .await
-                    .context("failed to write to the local server socket")?;
+                Some(host_bind_response::Response::Heartbeat(heartbeat)) => {
+                    liveness.reply(heartbeat).await?;
+                }
+                _ => {}
             }
+        }
 
-            Ok::<_, anyhow::Error>(())
+        Ok(())
+    }
+}
+
+pub mod udp {
+    use std::{
+        collections::{hash_map::Entry, HashMap},
+        io::ErrorKind,
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use crate::server::Server;
+    use anyhow::Context;
+    use rrp::grpc::{
+        udp_bind_request, udp_bind_response, Datagram, UdpBindRequest, UdpBindRequestMetadata,
+    };
+    use rrp::handshake::capabilities;
+    use tokio::{net::UdpSocket, select, sync::mpsc, task::JoinHandle};
+    use tokio_stream::StreamExt;
+
+    use super::{
+        heartbeat::{bind_requests, Liveness},
+        reconnect::Reconnect,
+        tcp::HEARTBEAT_BACK_PRESSURE,
+    };
+
+    // The largest payload a single udp datagram can carry
+    const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+    // The amount of datagrams from the local server to the proxy
+    // that we'll buffer before we stop reading from the local server
+    const LOCAL_SERVER_DATAGRAM_BACK_PRESSURE: usize = 64;
+
+    // A visitor session, the visitor's datagrams are sent to the local server
+    // from a dedicated socket so the local server's replies can be told apart
+    struct Session {
+        socket: Arc<UdpSocket>,
+        relay: JoinHandle<()>,
+    }
+
+    impl Drop for Session {
+        fn drop(&mut self) {
+            self.relay.abort();
+        }
+    }
+
+    pub async fn expose_port(
+        server: &Server,
+        local_port: u16,
+        external_port: Option<u16>,
+        bind_address: Option<IpAddr>,
+    ) -> anyhow::Result<()> {
+        // a lost binding is restored on the port it was assigned, even if the OS chose it,
+        // the visitors' sessions can't survive it though
+        let mut external_port = external_port;
+        let mut reconnect = Reconnect::default();
+        loop {
+            let result = bind_port(
+                server,
+                local_port,
+                &mut external_port,
+                bind_address,
+                &mut reconnect,
+            )
+            .await;
+            reconnect.lost(result).await?;
+        }
+    }
+
+    // Binds a port and relays its datagrams until the binding is lost
+    async fn bind_port(
+        server: &Server,
+        local_port: u16,
+        external_port: &mut Option<u16>,
+        bind_address: Option<IpAddr>,
+        reconnect: &mut Reconnect,
+    ) -> anyhow::Result<()> {
+        let mut client = match bind_address {
+            Some(_) => {
+                server
+                    .open_client(&[capabilities::UDP, capabilities::BIND_ADDRESS])
+                    .await?
+            }
+            None => server.open_client(&[capabilities::UDP]).await?,
         };
 
-        // Run both futures on the same
-        let (r1, r2) = tokio::join!(from_local_server, from_client);
-        r1?;
-        r2?;
+        // a channel for the heartbeats we echo back, they're sent ahead of the datagrams
+        // so a busy local server can't hold them back until the server gives up on us
+        let (replies, replies_stream) = bind_requests(
+            UdpBindRequest {
+                request: Some(udp_bind_request::Request::Metadata(
+                    UdpBindRequestMetadata {
+                        port: external_port.map(|port| port as i32),
+                        bind_address: bind_address.map(|ip| ip.to_string()),
+                    },
+                )),
+            },
+            HEARTBEAT_BACK_PRESSURE,
+        );
+        let mut liveness = Liveness::new(replies);
+
+        // a channel for the datagrams we send back to the visitors through the proxy
+        let (tx, mut datagrams) = mpsc::channel(LOCAL_SERVER_DATAGRAM_BACK_PRESSURE);
+        let local_server_stream = async_stream::stream! {
+            let mut replies = std::pin::pin!(replies_stream);
+            loop {
+                let request = select! {
+                    biased;
+                    request = replies.next() => request,
+                    Some(request) = datagrams.recv() => Some(request),
+                };
+                match request {
+                    Some(request) => yield request,
+                    None => break,
+                }
+            }
+        };
+
+        let mut datagrams_stream = client
+            .bind_udp(local_server_stream)
+            .await
+            .context("failed to expose the local port!")?
+            .into_inner();
+
+        let metadata = datagrams_stream
+            .message()
+            .await?
+            .and_then(|md| md.response)
+            .and_then(|md| match md {
+                udp_bind_response::Response::Metadata(md) => Some(md),
+                _ => None,
+            })
+            .context("the first message from the server should always contain metadata")?;
+
+        // we can trust the server to return a valid port number
+        let port: u16 = metadata.port.try_into().unwrap();
+        *external_port = Some(port);
+        reconnect.established();
+        println!(
+            "Reverse proxy listening on udp port: {} (bound on {})",
+            port, metadata.bind_address
+        );
+
+        let mut sessions = HashMap::new();
+        while let Some(message) = liveness.next(&mut datagrams_stream).await? {
+            match message.response {
+                Some(udp_bind_response::Response::Datagram(datagram)) => {
+                    let session = match sessions.entry(datagram.session_id) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            match open_session(datagram.session_id, local_port, tx.clone()).await {
+                                Ok(session) => entry.insert(session),
+                                // the datagram is lost, the visitor's next one tries again
+                                Err(err) => {
+                                    eprintln!("Failed to open a udp session: {}", err);
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+
+                    // the local server might not be listening, the datagram is simply lost
+                    let _ = session.socket.send(&datagram.data).await;
+                }
+                Some(udp_bind_response::Response::Closed(closed)) => {
+                    sessions.remove(&closed.session_id);
+                }
+                Some(udp_bind_response::Response::Heartbeat(heartbeat)) => {
+                    liveness.reply(heartbeat).await?;
+                }
+                _ => {}
+            }
+        }
 
         Ok(())
     }
+
+    async fn open_session(
+        session_id: u64,
+        local_port: u16,
+        tx: mpsc::Sender<UdpBindRequest>,
+    ) -> anyhow::Result<Session> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .context("failed to open a local udp socket")?;
+        socket
+            .connect((Ipv4Addr::LOCALHOST, local_port))
+            .await
+            .with_context(|| format!("failed to connect to the local server at: {}", local_port))?;
+        let socket = Arc::new(socket);
+
+        // relay the local server's replies back to the visitor
+        let relay = tokio::spawn({
+            let socket = socket.clone();
+            async move {
+                let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+                loop {
+                    let rcount = match socket.recv(&mut data).await {
+                        Ok(rcount) => rcount,
+                        // a previous datagram could not be delivered to the local server
+                        Err(err) if err.kind() == ErrorKind::ConnectionRefused => continue,
+                        Err(_) => break,
+                    };
+
+                    let datagram = Datagram {
+                        session_id,
+                        data: data[..rcount].to_vec(),
+                    };
+                    let request = UdpBindRequest {
+                        request: Some(udp_bind_request::Request::Datagram(datagram)),
+                    };
+                    if tx.send(request).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Session { socket, relay })
+    }
 }
@@ -1,16 +1,63 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Context;
 use rrp::{
     auth::{generate_token, hash_token, TokenHash},
+    grpc::{reverse_proxy_client::ReverseProxyClient, HelloRequest},
+    handshake::{self, capabilities},
     project_dir,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{fs, io::AsyncWriteExt};
-use tonic::{service::interceptor::InterceptedService, transport::Certificate};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{Certificate, Channel},
+    Code, Request, Status,
+};
 
 const SERVER_LIST_FILE_NAME: &str = "servers.toml";
 
+// The features this client supports, as advertised to the server
+const CLIENT_CAPABILITIES: &[&str] = &[
+    capabilities::TCP,
+    capabilities::UDP,
+    capabilities::HTTP,
+    capabilities::EDGE_AUTH,
+    capabilities::TLS,
+    capabilities::PROXY_PROTOCOL,
+    capabilities::BIND_ADDRESS,
+    capabilities::VISITOR_FILTERS,
+    capabilities::LOAD_BALANCING,
+    capabilities::CONNECT,
+    capabilities::PRIVATE,
+    capabilities::MULTIPLEXING,
+];
+
+// The channel is long-lived and carries every tunneled connection,
+// so make sure a silently dropped connection is noticed
+const CHANNEL_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// A channel to the server that attaches the auth token to every request.
+//
+// the channel multiplexes all requests over a single http2 connection,
+// cloning it is cheap and does not open a new connection
+pub type AuthenticatedChannel = InterceptedService<Channel, AuthInterceptor>;
+
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    token: MetadataValue<Ascii>,
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request
+            .metadata_mut()
+            .insert(rrp::auth::METADATA_TOKEN, self.token.clone());
+        Ok(request)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerList {
     #[serde(flatten)]
@@ -94,35 +141,80 @@ pub struct Server {
 }
 
 impl Server {
-    pub async fn open_grpc_channel(
-        &self,
-    ) -> anyhow::Result<
-        InterceptedService<
-            tonic::transport::Channel,
-            impl Fn(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> + Clone,
-        >,
-    > {
-        let token: tonic::metadata::MetadataValue<_> = self.token.clone().parse()?;
-        let attach_auth_middleware = move |mut request: tonic::Request<()>| {
-            request
-                .metadata_mut()
-                .insert(rrp::auth::METADATA_TOKEN, token.clone());
-            Ok(request)
-        };
+    pub async fn open_grpc_channel(&self) -> anyhow::Result<AuthenticatedChannel> {
+        let token = self.token.clone().parse()?;
 
         let tls = tonic::transport::ClientTlsConfig::new()
             .ca_certificate(Certificate::from_pem(&self.certificate))
             .domain_name(self.certificate_hostname.clone());
 
-        let channel = tonic::transport::Channel::from_shared(self.url.to_string())
+        let channel = Channel::from_shared(self.url.to_string())
             .context("Failed to parse the server details")?
             .tls_config(tls)
             .context("Failed to parse the server certificate")?
+            .http2_keep_alive_interval(CHANNEL_KEEP_ALIVE_INTERVAL)
+            .keep_alive_while_idle(true)
+            .http2_adaptive_window(true)
             .connect()
             .await?;
 
         // attach authentication token to all requests
-        Ok(InterceptedService::new(channel, attach_auth_middleware))
+        Ok(InterceptedService::new(channel, AuthInterceptor { token }))
+    }
+
+    // Opens a channel to the server and introduces ourselves,
+    // making sure that the server speaks our protocol and supports the capabilities we need
+    pub async fn open_client(
+        &self,
+        required: &[&str],
+    ) -> anyhow::Result<ReverseProxyClient<AuthenticatedChannel>> {
+        let mut client = ReverseProxyClient::new(self.open_grpc_channel().await?);
+
+        let hello = client
+            .hello(HelloRequest {
+                protocol_version: handshake::PROTOCOL_VERSION,
+                build_version: handshake::BUILD_VERSION.to_string(),
+                capabilities: CLIENT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            })
+            .await;
+        let hello = match hello {
+            Ok(hello) => hello.into_inner(),
+            Err(status) if status.code() == Code::Unimplemented => anyhow::bail!(
+                "the server is too old for this client (build {}), please upgrade the server",
+                handshake::BUILD_VERSION
+            ),
+            Err(status) if status.code() == Code::FailedPrecondition => {
+                anyhow::bail!("{}", status.message())
+            }
+            Err(status) => return Err(status).context("failed to greet the server"),
+        };
+
+        if !handshake::is_compatible(hello.protocol_version) {
+            anyhow::bail!(
+                "the server speaks protocol version {} (build {}), but this client speaks versions {} to {} (build {}), please upgrade the {}",
+                hello.protocol_version,
+                hello.build_version,
+                handshake::MIN_PROTOCOL_VERSION,
+                handshake::PROTOCOL_VERSION,
+                handshake::BUILD_VERSION,
+                match hello.protocol_version < handshake::MIN_PROTOCOL_VERSION {
+                    true => "server",
+                    false => "client",
+                }
+            );
+        }
+        for capability in required {
+            if !hello.capabilities.iter().any(|c| c == capability) {
+                anyhow::bail!(
+                    "the server (build {}) doesn't support \"{}\", it supports: {}",
+                    hello.build_version,
+                    capability,
+                    hello.capabilities.join(", ")
+                );
+            }
+        }
+
+        Ok(client)
     }
 
     pub fn hashed_token(&self) -> anyhow::Result<TokenHash> {
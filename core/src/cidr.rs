@@ -0,0 +1,193 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid cidr: {0}")]
+    InvalidCidr(String),
+}
+
+// A range of ip addresses, e.g. "10.0.0.0/8" or "2001:db8::/32"
+//
+// a plain address is treated as a range that contains only itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 visitors of a dual-stack listener show up as ipv4-mapped ipv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                mask(u32::from(network), u32::from(ip), self.prefix, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                mask(u128::from(network), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Checks whether the first `prefix` bits of both addresses are equal
+fn mask<T>(network: T, ip: T, prefix: u8, bits: u8) -> bool
+where
+    T: std::ops::Shr<u8, Output = T> + PartialEq,
+{
+    if prefix == 0 {
+        return true;
+    }
+
+    let shift = bits - prefix;
+    network >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidCidr(s.to_string());
+
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = network.trim().parse().map_err(|_| invalid())?;
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn contains(range: &str, ip: &str) -> bool {
+        cidr(range).contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr(" 10.0.0.0 / 8 ").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("::/0").to_string(), "::/0");
+        // a plain address is a range of its own
+        assert_eq!(cidr("10.0.0.1"), cidr("10.0.0.1/32"));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn rejects_invalid_cidrs() {
+        for s in [
+            "",
+            "/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "example.com/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(contains("10.0.0.0/8", "10.255.255.255"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.0"));
+        assert!(contains("192.168.1.0/24", "192.168.1.200"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.1"));
+
+        assert!(contains("10.0.0.1/32", "10.0.0.1"));
+        assert!(!contains("10.0.0.1/32", "10.0.0.2"));
+        assert!(contains("0.0.0.0/0", "1.2.3.4"));
+        assert!(contains("0.0.0.0/0", "255.255.255.255"));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("::/0", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+    }
+
+    #[test]
+    fn ignores_host_bits() {
+        // only the prefix of the network address is compared
+        assert!(contains("10.1.2.3/8", "10.200.0.1"));
+        assert!(!contains("10.1.2.3/8", "11.1.2.3"));
+        assert!(contains("2001:db8::1/32", "2001:db8:ffff::1"));
+    }
+
+    #[test]
+    fn doesnt_mix_families() {
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+        assert!(!contains("10.0.0.0/8", "::a00:1"));
+        // unless the ipv6 address is an ipv4-mapped one
+        assert!(contains("10.0.0.0/8", "::ffff:10.0.0.1"));
+        assert!(!contains("::ffff:0.0.0.0/96", "10.0.0.1"));
+    }
+
+    #[test]
+    fn masks_prefixes() {
+        assert!(mask(0xff00_u32, 0xff00, 0, 32));
+        assert!(mask(0x0a00_0000_u32, 0xffff_ffff, 0, 32));
+        assert!(mask(0x0a00_0000_u32, 0x0aff_ffff, 8, 32));
+        assert!(!mask(0x0a00_0000_u32, 0x0b00_0000, 8, 32));
+        assert!(mask(0x0a00_0001_u32, 0x0a00_0001, 32, 32));
+        assert!(!mask(0x0a00_0001_u32, 0x0a00_0002, 32, 32));
+        assert!(mask(1_u128, 1, 128, 128));
+        assert!(!mask(1_u128, 2, 128, 128));
+        assert!(mask(1_u128 << 127, u128::MAX, 1, 128));
+    }
+}
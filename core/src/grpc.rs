@@ -1,3 +1,133 @@
 tonic::include_proto!("generated_proto");
 
 pub use self::rrp::*;
+
+impl Control {
+    pub fn fin() -> Self {
+        Self {
+            kind: control::Kind::Fin as i32,
+            reason: String::new(),
+        }
+    }
+
+    pub fn rst() -> Self {
+        Self {
+            kind: control::Kind::Rst as i32,
+            reason: String::new(),
+        }
+    }
+
+    pub fn error(reason: impl ToString) -> Self {
+        Self {
+            kind: control::Kind::Error as i32,
+            reason: reason.to_string(),
+        }
+    }
+
+    // The frame that tells the other side about a failed socket operation
+    pub fn from_io_error(err: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                Self::rst()
+            }
+            _ => Self::error(err),
+        }
+    }
+}
+
+// A relayed connection's frame, either its data or a control frame
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Packet(Packet),
+    Control(Control),
+}
+
+impl From<Packet> for Frame {
+    fn from(packet: Packet) -> Self {
+        Self::Packet(packet)
+    }
+}
+
+impl From<Control> for Frame {
+    fn from(control: Control) -> Self {
+        Self::Control(control)
+    }
+}
+
+// Implements the conversions between a relay message and its frames
+macro_rules! relay_message {
+    ($message:ident, $field:ident, $oneof:ident::$kind:ident) => {
+        impl $message {
+            // The frame the message carries, None if it carries metadata
+            pub fn into_frame(self) -> Option<Frame> {
+                match self.$field? {
+                    $oneof::$kind::Packet(packet) => Some(Frame::Packet(packet)),
+                    $oneof::$kind::Control(control) => Some(Frame::Control(control)),
+                    _ => None,
+                }
+            }
+        }
+
+        impl From<Frame> for $message {
+            fn from(frame: Frame) -> Self {
+                let $field = match frame {
+                    Frame::Packet(packet) => $oneof::$kind::Packet(packet),
+                    Frame::Control(control) => $oneof::$kind::Control(control),
+                };
+
+                Self {
+                    $field: Some($field),
+                }
+            }
+        }
+    };
+}
+
+relay_message!(TcpAcceptRequest, request, tcp_accept_request::Request);
+relay_message!(TcpAcceptResponse, response, tcp_accept_response::Response);
+relay_message!(TcpConnectRequest, request, tcp_connect_request::Request);
+relay_message!(TcpConnectResponse, response, tcp_connect_response::Response);
+relay_message!(PrivateVisitRequest, request, private_visit_request::Request);
+relay_message!(
+    PrivateVisitResponse,
+    response,
+    private_visit_response::Response
+);
+
+// Implements the conversions between a bind message and the heartbeats it carries
+macro_rules! heartbeat_message {
+    ($message:ident, $field:ident, $oneof:ident::$kind:ident) => {
+        impl $message {
+            // The heartbeat the message carries, None if it carries anything else
+            pub fn into_heartbeat(self) -> Option<Heartbeat> {
+                match self.$field? {
+                    $oneof::$kind::Heartbeat(heartbeat) => Some(heartbeat),
+                    _ => None,
+                }
+            }
+        }
+
+        impl From<Heartbeat> for $message {
+            fn from(heartbeat: Heartbeat) -> Self {
+                Self {
+                    $field: Some($oneof::$kind::Heartbeat(heartbeat)),
+                }
+            }
+        }
+    };
+}
+
+heartbeat_message!(TcpBindRequest, request, tcp_bind_request::Request);
+heartbeat_message!(TcpBindResponse, response, tcp_bind_response::Response);
+heartbeat_message!(UdpBindRequest, request, udp_bind_request::Request);
+heartbeat_message!(UdpBindResponse, response, udp_bind_response::Response);
+heartbeat_message!(HostBindRequest, request, host_bind_request::Request);
+heartbeat_message!(HostBindResponse, response, host_bind_response::Response);
+heartbeat_message!(PrivateBindRequest, request, private_bind_request::Request);
+heartbeat_message!(
+    PrivateBindResponse,
+    response,
+    private_bind_response::Response
+);
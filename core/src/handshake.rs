@@ -0,0 +1,45 @@
+// The version handshake that clients and servers open every session with
+//
+// the protocol version is bumped on every breaking change to the wire protocol,
+// features that are added without breaking it are advertised as capabilities instead
+
+// The protocol version this build speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+// The oldest protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+// The version of this build
+pub const BUILD_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// The features a peer may support
+pub mod capabilities {
+    // Binding public tcp ports
+    pub const TCP: &str = "tcp";
+    // Binding public udp ports
+    pub const UDP: &str = "udp";
+    // Routing http bindings by their host header on a shared port
+    pub const HTTP: &str = "http";
+    // Requiring credentials from the visitors of http bindings
+    pub const EDGE_AUTH: &str = "edge-auth";
+    // Routing tls bindings by their server name on a shared port
+    pub const TLS: &str = "tls";
+    // Sending PROXY protocol headers to local servers
+    pub const PROXY_PROTOCOL: &str = "proxy-protocol";
+    // Binding ports on a specific address of the server
+    pub const BIND_ADDRESS: &str = "bind-address";
+    // Letting only some visitors through to a binding by their address
+    pub const VISITOR_FILTERS: &str = "visitor-filters";
+    // Sharing a port or a hostname between bindings, and spreading the visitors across them
+    pub const LOAD_BALANCING: &str = "load-balancing";
+    // Opening outbound connections from the server (local forwarding & SOCKS)
+    pub const CONNECT: &str = "connect";
+    // Client to client tunnels through private services
+    pub const PRIVATE: &str = "private";
+    // Relaying every connection as a stream on a single multiplexed channel
+    pub const MULTIPLEXING: &str = "multiplexing";
+}
+
+// Whether this build can talk to a peer that speaks the given protocol version
+pub fn is_compatible(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
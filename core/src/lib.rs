@@ -2,7 +2,10 @@ use directories::ProjectDirs;
 use std::{fs::create_dir_all, sync::OnceLock};
 
 pub mod auth;
+pub mod cidr;
 pub mod grpc;
+pub mod handshake;
+pub mod proxy_protocol;
 pub mod tls;
 
 pub fn project_dir() -> &'static ProjectDirs {
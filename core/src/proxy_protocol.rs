@@ -0,0 +1,374 @@
+// HAProxy's PROXY protocol, a header that is sent ahead of a proxied
+// connection to let the receiving end know who the real peer is.
+//
+// see: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// The first bytes of every v1 header
+pub const V1_PREFIX: &[u8; 6] = b"PROXY ";
+// The first bytes of every v2 header
+pub const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// The longest possible v1 header, including the line terminator
+const V1_MAX_SIZE: usize = 107;
+// The fixed part of a v2 header: the signature, command, family and length
+const V2_HEADER_SIZE: usize = 16;
+
+const V2_VERSION_PROXY_COMMAND: u8 = 0x21;
+const V2_VERSION_LOCAL_COMMAND: u8 = 0x20;
+const V2_TCP_OVER_IPV4: u8 = 0x11;
+const V2_TCP_OVER_IPV6: u8 = 0x21;
+const V2_ADDRESS_FAMILY_IPV4: u8 = 0x10;
+const V2_ADDRESS_FAMILY_IPV6: u8 = 0x20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the connection does not start with a PROXY protocol header")]
+    MissingHeader,
+    #[error("malformed PROXY protocol header")]
+    MalformedHeader,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    // More data is needed to decode the header
+    Incomplete,
+    Complete {
+        // The connection's real addresses (source, destination),
+        // not present when the sender did not proxy a connection (e.g. health checks)
+        addresses: Option<(SocketAddr, SocketAddr)>,
+
+        // The size of the header, the connection's data starts right after it
+        length: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    // The human readable header
+    V1,
+    // The binary header
+    V2,
+}
+
+// Encodes the header of a tcp connection that was made from source to destination
+pub fn encode(version: Version, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    // both addresses need to be of the same family
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V6(destination)) => {
+            (IpAddr::V6(source.to_ipv6_mapped()), IpAddr::V6(destination))
+        }
+        (IpAddr::V6(source), IpAddr::V4(destination)) => {
+            (IpAddr::V6(source), IpAddr::V6(destination.to_ipv6_mapped()))
+        }
+        addresses => addresses,
+    };
+
+    match version {
+        Version::V1 => {
+            let family = match source_ip {
+                IpAddr::V4(_) => "TCP4",
+                IpAddr::V6(_) => "TCP6",
+            };
+
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source_ip,
+                destination_ip,
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut addresses = Vec::new();
+            let family = match (source_ip, destination_ip) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    addresses.extend_from_slice(&source.octets());
+                    addresses.extend_from_slice(&destination.octets());
+                    V2_TCP_OVER_IPV4
+                }
+                (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                    addresses.extend_from_slice(&source.octets());
+                    addresses.extend_from_slice(&destination.octets());
+                    V2_TCP_OVER_IPV6
+                }
+                _ => unreachable!("the addresses were converted to the same family"),
+            };
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(V2_VERSION_PROXY_COMMAND);
+            header.push(family);
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+
+            header
+        }
+    }
+}
+
+// Decodes a (v1 or v2) header from the beginning of a connection's data
+pub fn decode(data: &[u8]) -> Result<Decoded> {
+    if starts_with(data, V1_PREFIX) {
+        return decode_v1(data);
+    }
+    if starts_with(data, V2_SIGNATURE) {
+        return decode_v2(data);
+    }
+
+    Err(Error::MissingHeader)
+}
+
+// Checks whether the data starts with the prefix,
+// or could start with it once more data arrives
+fn starts_with(data: &[u8], prefix: &[u8]) -> bool {
+    let length = data.len().min(prefix.len());
+    data[..length] == prefix[..length]
+}
+
+fn decode_v1(data: &[u8]) -> Result<Decoded> {
+    let Some(end) = data
+        .windows(2)
+        .take(V1_MAX_SIZE - 1)
+        .position(|window| window == b"\r\n")
+    else {
+        return match data.len() >= V1_MAX_SIZE {
+            true => Err(Error::MalformedHeader),
+            false => Ok(Decoded::Incomplete),
+        };
+    };
+
+    let line = std::str::from_utf8(&data[..end]).map_err(|_| Error::MalformedHeader)?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let addresses = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr> {
+                Ok(SocketAddr::new(
+                    ip.parse().map_err(|_| Error::MalformedHeader)?,
+                    port.parse().map_err(|_| Error::MalformedHeader)?,
+                ))
+            };
+
+            Some((
+                parse(source, source_port)?,
+                parse(destination, destination_port)?,
+            ))
+        }
+        _ => return Err(Error::MalformedHeader),
+    };
+
+    Ok(Decoded::Complete {
+        addresses,
+        length: end + 2,
+    })
+}
+
+fn decode_v2(data: &[u8]) -> Result<Decoded> {
+    if data.len() < V2_HEADER_SIZE {
+        return Ok(Decoded::Incomplete);
+    }
+
+    let command = data[12];
+    let family = data[13];
+    let length = V2_HEADER_SIZE + u16::from_be_bytes([data[14], data[15]]) as usize;
+    let Some(payload) = data.get(V2_HEADER_SIZE..length) else {
+        return Ok(Decoded::Incomplete);
+    };
+
+    let addresses = match command {
+        V2_VERSION_LOCAL_COMMAND => None,
+        V2_VERSION_PROXY_COMMAND => match family & 0xf0 {
+            V2_ADDRESS_FAMILY_IPV4 if payload.len() >= 12 => {
+                let ip = |offset: usize| {
+                    let octets: [u8; 4] = payload[offset..offset + 4].try_into().unwrap();
+                    IpAddr::V4(Ipv4Addr::from(octets))
+                };
+                let port =
+                    |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+
+                Some((
+                    SocketAddr::new(ip(0), port(8)),
+                    SocketAddr::new(ip(4), port(10)),
+                ))
+            }
+            V2_ADDRESS_FAMILY_IPV6 if payload.len() >= 36 => {
+                let ip = |offset: usize| {
+                    let octets: [u8; 16] = payload[offset..offset + 16].try_into().unwrap();
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                let port =
+                    |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+
+                Some((
+                    SocketAddr::new(ip(0), port(32)),
+                    SocketAddr::new(ip(16), port(34)),
+                ))
+            }
+            V2_ADDRESS_FAMILY_IPV4 | V2_ADDRESS_FAMILY_IPV6 => return Err(Error::MalformedHeader),
+            // unix sockets and unspecified families carry no address we can use
+            _ => None,
+        },
+        _ => return Err(Error::MalformedHeader),
+    };
+
+    Ok(Decoded::Complete { addresses, length })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(source: &str, destination: &str) -> (SocketAddr, SocketAddr) {
+        (source.parse().unwrap(), destination.parse().unwrap())
+    }
+
+    fn round_trip(version: Version, source: &str, destination: &str) {
+        let (source, destination) = addresses(source, destination);
+        let mut data = encode(version, source, destination);
+        let length = data.len();
+        data.extend_from_slice(b"data");
+
+        assert_eq!(
+            decode(&data).unwrap(),
+            Decoded::Complete {
+                addresses: Some((source, destination)),
+                length
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_ipv4() {
+        round_trip(Version::V1, "1.2.3.4:5678", "10.0.0.1:80");
+        round_trip(Version::V2, "1.2.3.4:5678", "10.0.0.1:80");
+    }
+
+    #[test]
+    fn round_trips_ipv6() {
+        round_trip(Version::V1, "[2001:db8::1]:5678", "[::1]:443");
+        round_trip(Version::V2, "[2001:db8::1]:5678", "[::1]:443");
+    }
+
+    #[test]
+    fn encodes_mixed_families_as_ipv6() {
+        let (source, destination) = addresses("1.2.3.4:5678", "[::1]:443");
+        let mapped = SocketAddr::new(IpAddr::V6(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped()), 5678);
+
+        for version in [Version::V1, Version::V2] {
+            let data = encode(version, source, destination);
+            assert_eq!(
+                decode(&data).unwrap(),
+                Decoded::Complete {
+                    addresses: Some((mapped, destination)),
+                    length: data.len()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_headers_are_incomplete() {
+        let (source, destination) = addresses("[2001:db8::1]:5678", "[::1]:443");
+        for version in [Version::V1, Version::V2] {
+            let data = encode(version, source, destination);
+            for end in 0..data.len() {
+                assert_eq!(decode(&data[..end]).unwrap(), Decoded::Incomplete);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_data_without_a_header() {
+        assert!(matches!(
+            decode(b"GET / HTTP/1.1\r\n"),
+            Err(Error::MissingHeader)
+        ));
+        assert!(matches!(
+            decode(b"\r\n\r\n\0\r\nQUIZ\n\x21\x11\0\x0c"),
+            Err(Error::MissingHeader)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        for data in [
+            &b"PROXY TCP5 1.2.3.4 10.0.0.1 5678 80\r\n"[..],
+            b"PROXY TCP4 1.2.3.4 10.0.0.1 5678\r\n",
+            b"PROXY TCP4 1.2.3.4 10.0.0.1 5678 99999\r\n",
+            b"PROXY TCP4 1.2.3.400 10.0.0.1 5678 80\r\n",
+        ] {
+            assert!(matches!(decode(data), Err(Error::MalformedHeader)));
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_v1_lines() {
+        let mut data = b"PROXY UNKNOWN ".to_vec();
+        data.resize(V1_MAX_SIZE, b'x');
+        assert!(matches!(decode(&data), Err(Error::MalformedHeader)));
+
+        // the terminator has to be within the longest possible header
+        data.extend_from_slice(b"\r\n");
+        assert!(matches!(decode(&data), Err(Error::MalformedHeader)));
+    }
+
+    #[test]
+    fn decodes_v1_unknown() {
+        let data = b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\ndata";
+        assert_eq!(
+            decode(data).unwrap(),
+            Decoded::Complete {
+                addresses: None,
+                length: data.len() - 4
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_v2_local() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[V2_VERSION_LOCAL_COMMAND, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            decode(&data).unwrap(),
+            Decoded::Complete {
+                addresses: None,
+                length: V2_HEADER_SIZE
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_v2_unspecified_family() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[V2_VERSION_PROXY_COMMAND, 0x00, 0x00, 0x04]);
+        data.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            decode(&data).unwrap(),
+            Decoded::Complete {
+                addresses: None,
+                length: V2_HEADER_SIZE + 4
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_v2_headers() {
+        // an unknown version and command
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x31, V2_TCP_OVER_IPV4, 0x00, 0x00]);
+        assert!(matches!(decode(&data), Err(Error::MalformedHeader)));
+
+        // addresses that are too short for their family
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[V2_VERSION_PROXY_COMMAND, V2_TCP_OVER_IPV6, 0x00, 0x0c]);
+        data.extend_from_slice(&[0; 12]);
+        assert!(matches!(decode(&data), Err(Error::MalformedHeader)));
+    }
+}
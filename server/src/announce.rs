@@ -0,0 +1,77 @@
+use rrp::grpc::{Heartbeat, TcpNewConnection};
+use tokio::select;
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+use crate::{
+    config::Config, connections::Binding, heartbeat::Heartbeats, shutdown::Shutdown,
+    visitor::Visitor,
+};
+
+// Announces the visitors of a binding to its client, along with the heartbeats
+//
+// every visitor is queued as a pending connection, which the client claims by the announced id.
+// the requests are the messages the client sends after the metadata, only heartbeats are expected.
+// the stream ends once the visitors run out, the client goes away or the server shuts down
+pub fn announce<T, V, R>(
+    config: &'static Config,
+    shutdown: &'static Shutdown,
+    queue: Binding,
+    visitors: V,
+    mut requests: R,
+    announcement: fn(TcpNewConnection) -> T,
+) -> impl Stream<Item = Result<T, Status>>
+where
+    T: From<Heartbeat>,
+    V: Stream<Item = Visitor>,
+    R: Stream<Item = Result<Option<Heartbeat>, Status>> + Unpin,
+{
+    async_stream::stream! {
+        let mut visitors = std::pin::pin!(visitors);
+        let mut heartbeats = Heartbeats::new(config);
+        loop {
+            let visitor = select! {
+                visitor = visitors.next() => match visitor {
+                    Some(visitor) => visitor,
+                    None => break,
+                },
+
+                status = shutdown.unbind() => {
+                    // the connections that were already relayed are left to drain
+                    yield Err(status);
+                    break;
+                }
+
+                heartbeat = heartbeats.tick() => match heartbeat {
+                    Ok(heartbeat) => {
+                        yield Ok(heartbeat.into());
+                        continue;
+                    }
+                    // the client is gone, dropping the binding frees whatever it's bound to
+                    // and dropping the queue closes the pending connections
+                    Err(err) => {
+                        yield Err(err);
+                        break;
+                    }
+                },
+
+                msg = requests.next() => match heartbeats.receive(msg) {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(err) => {
+                        yield Err(err);
+                        break;
+                    }
+                },
+            };
+            let info = visitor.info();
+
+            // save the connection in the queue and let the client know that there is a new pending connection
+            let connection_id = queue.insert(visitor);
+            yield Ok(announcement(TcpNewConnection {
+                info: Some(info),
+                connection_id,
+            }));
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     fs::File,
     io::{Read, Write},
+    net::SocketAddr,
     path::Path,
 };
 
@@ -11,6 +12,8 @@ use rrp::auth::{hash_token, TokenHash, METADATA_TOKEN};
 use serde::{Deserialize, Serialize};
 use tonic::{service::interceptor::InterceptedService, Request, Status};
 
+use crate::{bandwidth::Bandwidth, destination::DestinationRule, ports::PortRange};
+
 const CLIENTS_FILE_NAME: &str = "clients.toml";
 const TEMPLATE_CLIENTS_FILE_NAME: &str = "clients.toml.example";
 
@@ -27,6 +30,104 @@ pub struct Client {
     #[serde(skip_deserializing)]
     identifier: String,
     hashed_token: String,
+
+    // The destinations this client may connect to through the server,
+    // on top of the ones the server allows every client
+    #[serde(default)]
+    connect_destinations: Vec<DestinationRule>,
+
+    // The identifiers of the clients that may visit this client's private services
+    #[serde(default)]
+    private_visitors: Vec<String>,
+
+    // The ports this client may expose, within the server's port range,
+    // any port of the range if empty
+    #[serde(default)]
+    allowed_ports: Vec<PortRange>,
+
+    // The ports only this client may expose, even if they're outside of the server's port range
+    #[serde(default)]
+    reserved_ports: Vec<PortRange>,
+
+    // The rate limits of all of the client's traffic together
+    #[serde(default)]
+    bandwidth: Bandwidth,
+
+    // The rate limits of every one of the client's bindings on its own
+    #[serde(default)]
+    binding_bandwidth: Bandwidth,
+
+    // How many bindings the client may hold at once, unlimited if not present
+    max_bindings: Option<usize>,
+
+    // How many tunneled connections the client may hold at once, unlimited if not present
+    max_connections: Option<usize>,
+
+    // The clients of the same load balancing group may share their load balanced ports and hostnames,
+    // a client only shares them between its own bindings if not present
+    load_balancing_group: Option<String>,
+}
+
+impl Client {
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    // Whether the client may connect to an address that the host was resolved to
+    pub fn allows_destination(&self, host: &str, addr: SocketAddr) -> bool {
+        self.connect_destinations
+            .iter()
+            .any(|rule| rule.allows(host, addr))
+    }
+
+    // Whether a client may visit this client's private services, a client may always visit its own
+    pub fn allows_visitor(&self, client: &Client) -> bool {
+        client.identifier == self.identifier || self.private_visitors.contains(&client.identifier)
+    }
+
+    // The ports the client is limited to, None if it isn't limited
+    pub fn allowed_ports(&self) -> Option<&[PortRange]> {
+        (!self.allowed_ports.is_empty()).then_some(self.allowed_ports.as_slice())
+    }
+
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.allowed_ports()
+            .is_none_or(|ranges| ranges.iter().any(|range| range.contains(port)))
+    }
+
+    pub fn reserves_port(&self, port: u16) -> bool {
+        self.reserved_ports.iter().any(|range| range.contains(port))
+    }
+
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    pub fn binding_bandwidth(&self) -> &Bandwidth {
+        &self.binding_bandwidth
+    }
+
+    pub fn max_bindings(&self) -> Option<usize> {
+        self.max_bindings
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn load_balancing_group(&self) -> Option<&str> {
+        self.load_balancing_group.as_deref()
+    }
+}
+
+// Fetch the client that was injected into the request by the auth middleware
+#[allow(clippy::result_large_err)]
+pub fn authenticated_client<T>(request: &Request<T>) -> Result<Client, Status> {
+    request
+        .extensions()
+        .get::<Client>()
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("No valid auth token was provided"))
 }
 
 impl Auth {
@@ -41,6 +142,22 @@ impl Auth {
                 let mock_data = toml::toml! {
                     [A_unique_client_identifier]
                     hashed_token = "<An hex encoded hashed version of the client's token>"
+                    connect_destinations = ["db.internal:5432", "*.example.com:443", "10.0.0.0/8:*"]
+                    private_visitors = ["Another_client_identifier"]
+                    allowed_ports = ["9000-9100"]
+                    reserved_ports = ["9000"]
+                    max_bindings = 10
+                    max_connections = 100
+                    load_balancing_group = "web"
+
+                    // in bytes per second
+                    [A_unique_client_identifier.bandwidth]
+                    upload = 10485760
+                    download = 10485760
+                    burst = 1048576
+
+                    [A_unique_client_identifier.binding_bandwidth]
+                    upload = 5242880
                 };
                 let _ = file.write_all(toml::to_string_pretty(&mock_data).unwrap().as_bytes());
             }
@@ -53,8 +170,14 @@ impl Auth {
         client_file
             .read_to_string(&mut data)
             .context("failed to read the clients file")?;
+
+        Self::from_toml(&data)
+    }
+
+    // Parses the auth data out of the contents of a clients file
+    pub fn from_toml(data: &str) -> anyhow::Result<Auth> {
         let clients: HashMap<String, Client> =
-            toml::from_str(&data).context("failed to parse the clients file")?;
+            toml::from_str(data).context("failed to parse the clients file")?;
 
         Ok(Auth {
             client: clients
@@ -69,6 +192,13 @@ impl Auth {
         })
     }
 
+    // Whether the port is reserved for a client other than this one
+    pub fn reserved_by_other(&self, client: &Client, port: u16) -> bool {
+        self.client
+            .iter()
+            .any(|other| other.identifier != client.identifier && other.reserves_port(port))
+    }
+
     // Authenticate a client by token
     //
     // returns the client's info if recognized, otherwise None
@@ -79,6 +209,7 @@ impl Auth {
 }
 
 // Attach an authentication middleware to a service
+#[allow(clippy::result_large_err)]
 pub fn attach_auth<S>(
     shared_auth: &'static Auth,
     service: S,
@@ -0,0 +1,253 @@
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+
+use crate::auth::Client;
+
+// The rate limits of a client's traffic, in bytes per second, unlimited if not present
+//
+// directions are from the client's point of view,
+// it uploads the data it sends to its visitors and downloads the data they send it
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Bandwidth {
+    upload: Option<NonZeroU64>,
+    download: Option<NonZeroU64>,
+
+    // How many bytes may be sent at once after the traffic was idle for a while,
+    // a second's worth of traffic if not present
+    burst: Option<NonZeroU64>,
+}
+
+// Shapes a flow of traffic to a steady rate
+//
+// tokens are refilled at the rate up to the burst,
+// and a flow that takes more tokens than there are waits until it has paid them back
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: NonZeroU64, burst: Option<NonZeroU64>) -> Self {
+        let burst = burst.unwrap_or(rate).get() as f64;
+        Self {
+            rate: rate.get() as f64,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    // Waits until the amount of bytes may be sent
+    async fn take(&self, amount: usize) {
+        let debt = {
+            let mut state = self.refill();
+            state.tokens -= amount as f64;
+            -state.tokens
+        };
+
+        if debt > 0.0 {
+            sleep(Duration::from_secs_f64(debt / self.rate)).await;
+        }
+    }
+
+    // Takes the amount of bytes only if they may be sent right away
+    fn try_take(&self, amount: usize) -> bool {
+        let mut state = self.refill();
+        if state.tokens < amount as f64 {
+            return false;
+        }
+
+        state.tokens -= amount as f64;
+        true
+    }
+
+    fn refill(&self) -> MutexGuard<'_, BucketState> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.burst);
+        state.updated = now;
+
+        state
+    }
+}
+
+// The buckets of both directions of a single limit
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Buckets {
+    // None if both directions are unlimited
+    fn new(bandwidth: &Bandwidth) -> Option<Arc<Self>> {
+        if bandwidth.upload.is_none() && bandwidth.download.is_none() {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            upload: bandwidth
+                .upload
+                .map(|rate| TokenBucket::new(rate, bandwidth.burst)),
+            download: bandwidth
+                .download
+                .map(|rate| TokenBucket::new(rate, bandwidth.burst)),
+        }))
+    }
+}
+
+// The limits a relayed connection is subject to, every one of them needs to allow its traffic
+#[derive(Clone, Default)]
+pub struct Limits {
+    buckets: Vec<Arc<Buckets>>,
+}
+
+impl Limits {
+    // Waits until the client may send the amount of bytes to a visitor
+    pub async fn upload(&self, amount: usize) {
+        for buckets in &self.buckets {
+            if let Some(bucket) = &buckets.upload {
+                bucket.take(amount).await;
+            }
+        }
+    }
+
+    // Waits until a visitor may send the amount of bytes to the client
+    pub async fn download(&self, amount: usize) {
+        for buckets in &self.buckets {
+            if let Some(bucket) = &buckets.download {
+                bucket.take(amount).await;
+            }
+        }
+    }
+
+    // Whether the client may send the amount of bytes to a visitor right away,
+    // for traffic that is dropped rather than delayed when it's over the limits
+    //
+    // the bytes still count towards the limits that allowed them when a later one doesn't
+    pub fn try_upload(&self, amount: usize) -> bool {
+        self.buckets
+            .iter()
+            .filter_map(|buckets| buckets.upload.as_ref())
+            .all(|bucket| bucket.try_take(amount))
+    }
+
+    // Whether a visitor may send the amount of bytes to the client right away,
+    // for traffic that is dropped rather than delayed when it's over the limits
+    pub fn try_download(&self, amount: usize) -> bool {
+        self.buckets
+            .iter()
+            .filter_map(|buckets| buckets.download.as_ref())
+            .all(|bucket| bucket.try_take(amount))
+    }
+}
+
+// The limits of every client, shared by all of the client's connections
+#[derive(Default)]
+pub struct ClientLimits {
+    // Maps a client's identifier -> its buckets, None if the client is unlimited
+    clients: DashMap<String, Option<Arc<Buckets>>>,
+}
+
+impl ClientLimits {
+    // The limits of a client's traffic
+    pub fn client(&self, client: &Client) -> Limits {
+        let buckets = self
+            .clients
+            .entry(client.identifier().to_string())
+            .or_insert_with(|| Buckets::new(client.bandwidth()))
+            .clone();
+
+        Limits {
+            buckets: buckets.into_iter().collect(),
+        }
+    }
+
+    // The limits of a new binding's traffic, on top of the client's limits
+    pub fn binding(&self, client: &Client) -> Limits {
+        let mut limits = self.client(client);
+        limits
+            .buckets
+            .extend(Buckets::new(client.binding_bandwidth()));
+
+        limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(rate: u64, burst: Option<u64>) -> TokenBucket {
+        TokenBucket::new(
+            NonZeroU64::new(rate).unwrap(),
+            burst.and_then(NonZeroU64::new),
+        )
+    }
+
+    // How long it takes the bucket to let the amount of bytes through
+    async fn elapsed(bucket: &TokenBucket, amount: usize) -> Duration {
+        let start = Instant::now();
+        bucket.take(amount).await;
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_a_burst_through_right_away() {
+        let bucket = limited(100, Some(300));
+        assert_eq!(elapsed(&bucket, 300).await, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_debt_to_be_paid_back() {
+        let bucket = limited(100, None);
+        assert_eq!(elapsed(&bucket, 100).await, Duration::ZERO);
+        assert_eq!(elapsed(&bucket, 50).await, Duration::from_millis(500));
+
+        // a single take that is larger than the burst
+        let bucket = limited(100, None);
+        assert_eq!(elapsed(&bucket, 300).await, Duration::from_secs(2));
+        assert_eq!(elapsed(&bucket, 100).await, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_up_to_the_burst() {
+        let bucket = limited(100, Some(200));
+        bucket.take(200).await;
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(elapsed(&bucket, 100).await, Duration::ZERO);
+
+        // idling for a long time doesn't save up more than the burst
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(elapsed(&bucket, 200).await, Duration::ZERO);
+        assert_eq!(elapsed(&bucket, 100).await, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_to_go_into_debt_without_waiting() {
+        let bucket = limited(100, None);
+        assert!(bucket.try_take(60));
+        assert!(!bucket.try_take(60));
+        assert!(bucket.try_take(40));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(!bucket.try_take(60));
+        assert!(bucket.try_take(50));
+    }
+}
@@ -1,43 +1,193 @@
-use std::{io::Write, net::IpAddr};
+use std::{
+    io::Write,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
 use anyhow::Context;
 use clap::Parser;
-use rrp::project_dir;
+use rrp::{cidr::Cidr, project_dir};
 use serde::{Deserialize, Serialize};
 
+use crate::{destination::DestinationRule, ports::PortRange};
+
 const SERVER_CONFIG_FILE_NAME: &str = "server.toml";
 
 // The external interface
 pub struct Config {
+    // The unspecified ipv6 address ("::") listens on both ipv4 and ipv6,
+    // which needs ipv6 to be enabled on the host
     pub ip: IpAddr,
     pub port: u16,
+
+    // The addresses clients may expose ports on (at least one),
+    // the first one is used when a client doesn't ask for a specific one.
+    // just like the server's own ip, "::" exposes ports on both ipv4 and ipv6
+    pub bind_addresses: Vec<IpAddr>,
+
+    // The ports clients may expose, besides the ones that are reserved for them
+    pub port_range: PortRange,
+
+    // How long a udp visitor can stay silent before its session is dropped
+    pub udp_session_timeout: Duration,
+    // How many udp visitors a single binding tracks at once,
+    // the datagrams of new visitors are dropped while a binding is full
+    pub max_udp_sessions: usize,
+
+    // How long a visitor's connection waits for the client to accept it before it's closed
+    pub accept_timeout: Duration,
+
+    // How often a heartbeat is sent on every bind stream,
+    // and how many of them may go unanswered before the binding is torn down
+    pub heartbeat_interval: Duration,
+    pub heartbeat_misses: u32,
+
+    // How long the relayed connections may keep going once the server is shutting down
+    pub drain_timeout: Duration,
+
+    // The shared port for http bindings, http bindings are disabled if not present
+    pub http_port: Option<u16>,
+    // The shared port for tls bindings, tls bindings are disabled if not present
+    pub tls_port: Option<u16>,
+    // Single label hostnames are registered as subdomains of this domain
+    pub base_domain: Option<String>,
+
+    // Whether connections to the exposed ports may start with a PROXY protocol header,
+    // the header is only accepted from (and required of) the trusted sources
+    pub accept_proxy_protocol: bool,
+    pub proxy_protocol_trusted_sources: Vec<Cidr>,
+
+    // The visitors that are dropped on every exposed port, whatever the client allows
+    pub deny_visitors: Vec<Cidr>,
+
+    // The destinations clients are allowed to connect to through the server,
+    // local forwarding is disabled if empty
+    pub connect_destinations: Vec<DestinationRule>,
 }
 
 impl Config {
-    pub fn parse() -> Config {
+    pub fn parse() -> anyhow::Result<Config> {
         let cli = CliArgs::parse();
         let file = ConfigFile::parse_config().unwrap_or_else(|err| {
             eprintln!("{}", err);
             ConfigFile::default()
         });
 
-        // combine both config options into one final structure
-        Config {
+        Self::new(cli, file)
+    }
+
+    // The config of the contents of a config file, without any cli args
+    #[cfg(test)]
+    pub fn from_toml(data: &str) -> anyhow::Result<Config> {
+        let cli = CliArgs {
+            ip: None,
+            port: None,
+        };
+
+        Self::new(cli, toml::from_str(data)?)
+    }
+
+    // Combines both config options into one final structure, the cli args take precedence
+    fn new(cli: CliArgs, file: ConfigFile) -> anyhow::Result<Config> {
+        if file.bind_addresses.is_empty() {
+            anyhow::bail!("bind_addresses needs to hold at least one address");
+        }
+
+        Ok(Config {
             ip: cli.ip.unwrap_or(file.ip),
             port: cli.port.unwrap_or(file.port),
-        }
+            port_range: file.port_range,
+            bind_addresses: file.bind_addresses,
+            udp_session_timeout: Duration::from_secs(file.udp_session_timeout),
+            max_udp_sessions: file.max_udp_sessions,
+            accept_timeout: Duration::from_secs(file.accept_timeout),
+            heartbeat_interval: Duration::from_secs(file.heartbeat_interval.max(1)),
+            heartbeat_misses: file.heartbeat_misses.max(1),
+            drain_timeout: Duration::from_secs(file.drain_timeout),
+            http_port: file.http_port,
+            tls_port: file.tls_port,
+            base_domain: file.base_domain,
+            accept_proxy_protocol: file.accept_proxy_protocol,
+            proxy_protocol_trusted_sources: file.proxy_protocol_trusted_sources,
+            deny_visitors: file.deny_visitors,
+            connect_destinations: file.connect_destinations,
+        })
+    }
+
+    // The address ports are exposed on when a client doesn't ask for a specific one
+    pub fn default_bind_address(&self) -> IpAddr {
+        self.bind_addresses[0]
+    }
+
+    // Whether clients may expose ports on this address
+    pub fn allows_bind_address(&self, ip: IpAddr) -> bool {
+        self.bind_addresses.contains(&ip)
+    }
+
+    // Whether a connection from this address is expected to start with a PROXY protocol header
+    pub fn expects_proxy_protocol(&self, ip: IpAddr) -> bool {
+        self.accept_proxy_protocol
+            && self
+                .proxy_protocol_trusted_sources
+                .iter()
+                .any(|source| source.contains(ip))
+    }
+
+    // Whether visitors from this address are dropped on every exposed port
+    pub fn denies_visitor(&self, ip: IpAddr) -> bool {
+        self.deny_visitors.iter().any(|range| range.contains(ip))
+    }
+
+    // Whether clients may connect to an address that the host was resolved to
+    pub fn allows_destination(&self, host: &str, addr: SocketAddr) -> bool {
+        self.connect_destinations
+            .iter()
+            .any(|rule| rule.allows(host, addr))
     }
 }
 
 // Default values
 fn default_ip() -> IpAddr {
-    "0.0.0.0".parse().unwrap()
+    Ipv4Addr::UNSPECIFIED.into()
 }
 
 fn default_port() -> u16 {
     3600
 }
 
+// The privileged ports are left out
+fn default_port_range() -> PortRange {
+    PortRange::new(1024, u16::MAX)
+}
+
+fn default_bind_addresses() -> Vec<IpAddr> {
+    vec![Ipv4Addr::UNSPECIFIED.into()]
+}
+
+fn default_udp_session_timeout() -> u64 {
+    60
+}
+
+fn default_max_udp_sessions() -> usize {
+    1024
+}
+
+fn default_accept_timeout() -> u64 {
+    10
+}
+
+fn default_heartbeat_interval() -> u64 {
+    10
+}
+
+fn default_heartbeat_misses() -> u32 {
+    3
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
 // Config file
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -47,6 +197,49 @@ struct ConfigFile {
 
     #[serde(default = "default_port")]
     port: u16,
+
+    #[serde(default = "default_bind_addresses")]
+    bind_addresses: Vec<IpAddr>,
+
+    #[serde(default = "default_port_range")]
+    port_range: PortRange,
+
+    // In seconds
+    #[serde(default = "default_udp_session_timeout")]
+    udp_session_timeout: u64,
+
+    #[serde(default = "default_max_udp_sessions")]
+    max_udp_sessions: usize,
+
+    // In seconds
+    #[serde(default = "default_accept_timeout")]
+    accept_timeout: u64,
+
+    // In seconds
+    #[serde(default = "default_heartbeat_interval")]
+    heartbeat_interval: u64,
+
+    #[serde(default = "default_heartbeat_misses")]
+    heartbeat_misses: u32,
+
+    // In seconds
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
+
+    http_port: Option<u16>,
+    tls_port: Option<u16>,
+    base_domain: Option<String>,
+
+    #[serde(default)]
+    accept_proxy_protocol: bool,
+    #[serde(default)]
+    proxy_protocol_trusted_sources: Vec<Cidr>,
+
+    #[serde(default)]
+    deny_visitors: Vec<Cidr>,
+
+    #[serde(default)]
+    connect_destinations: Vec<DestinationRule>,
 }
 
 impl Default for ConfigFile {
@@ -0,0 +1,269 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use tonic::Status;
+
+use crate::{auth::Client, bandwidth::Limits, visitor::Visitor};
+
+// How long a connection is remembered after it was claimed or expired,
+// so a late claim gets a precise error instead of a generic one
+const SETTLED_CONNECTION_TTL: Duration = Duration::from_secs(60);
+
+pub type ConnectionId = u64;
+type BindingId = u64;
+
+enum Slot {
+    Pending {
+        // The binding the connection arrived on
+        binding: BindingId,
+        // The identifier of the client that owns the binding
+        owner: String,
+        visitor: Visitor,
+        // The limits of the binding's traffic
+        limits: Limits,
+    },
+    Claimed,
+    // The client didn't accept the connection in time, or closed its binding, and it was closed
+    Expired,
+}
+
+// The connections that are waiting to be accepted by a client
+//
+// every connection gets a unique id, which is announced to the client
+// in the bind stream and then used by the client to claim that exact connection
+pub struct PendingConnections {
+    accept_timeout: Duration,
+    last_id: AtomicU64,
+    slots: DashMap<ConnectionId, Slot>,
+}
+
+impl PendingConnections {
+    pub fn new(accept_timeout: Duration) -> Self {
+        Self {
+            accept_timeout,
+            last_id: AtomicU64::default(),
+            slots: DashMap::new(),
+        }
+    }
+
+    // Opens a queue for the connections of a new binding
+    //
+    // the binding's pending connections are closed once the returned value is dropped,
+    // and are relayed under the binding's limits once they're claimed
+    pub fn open(&'static self, owner: &Client, limits: Limits) -> Binding {
+        Binding {
+            connections: self,
+            id: self.next_id(),
+            owner: owner.identifier().to_string(),
+            limits,
+        }
+    }
+
+    // Takes a pending connection out of the queue
+    //
+    // only the client that owns the connection's binding may claim it
+    #[allow(clippy::result_large_err)]
+    pub fn claim(
+        &'static self,
+        client: &Client,
+        id: ConnectionId,
+    ) -> Result<(Visitor, Limits), Status> {
+        let mut slot = self.slots.get_mut(&id).ok_or_else(|| {
+            Status::not_found(format!("there is no pending connection with id: {}", id))
+        })?;
+
+        match &*slot {
+            Slot::Pending { owner, .. } if owner != client.identifier() => {
+                return Err(Status::permission_denied(format!(
+                    "the connection with id: {} belongs to another client",
+                    id
+                )));
+            }
+            Slot::Pending { .. } => {}
+            Slot::Claimed => {
+                return Err(Status::failed_precondition(format!(
+                    "the connection with id: {} was already claimed",
+                    id
+                )));
+            }
+            Slot::Expired => {
+                return Err(Status::deadline_exceeded(format!(
+                    "the connection with id: {} wasn't accepted in time",
+                    id
+                )));
+            }
+        }
+
+        match std::mem::replace(&mut *slot, Slot::Claimed) {
+            Slot::Pending {
+                visitor, limits, ..
+            } => Ok((visitor, limits)),
+            _ => unreachable!("only pending connections are claimed"),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn insert(&'static self, binding: &Binding, visitor: Visitor) -> ConnectionId {
+        let id = self.next_id();
+        self.slots.insert(
+            id,
+            Slot::Pending {
+                binding: binding.id,
+                owner: binding.owner.clone(),
+                visitor,
+                limits: binding.limits.clone(),
+            },
+        );
+
+        // close the connection if it's still pending when the deadline is reached
+        let accept_timeout = self.accept_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(accept_timeout).await;
+            if let Some(mut slot) = self.slots.get_mut(&id) {
+                if matches!(*slot, Slot::Pending { .. }) {
+                    *slot = Slot::Expired;
+                }
+            }
+
+            tokio::time::sleep(SETTLED_CONNECTION_TTL).await;
+            self.slots.remove(&id);
+        });
+
+        id
+    }
+}
+
+// The queue of a single binding's connections
+pub struct Binding {
+    connections: &'static PendingConnections,
+    id: BindingId,
+    owner: String,
+    limits: Limits,
+}
+
+impl Binding {
+    // Queues a new connection, returns the id it can be claimed by
+    pub fn insert(&self, visitor: Visitor) -> ConnectionId {
+        self.connections.insert(self, visitor)
+    }
+}
+
+impl Drop for Binding {
+    fn drop(&mut self) {
+        // nobody is left to accept the binding's pending connections,
+        // their slots settle and are removed by their expiry tasks like any other
+        for mut slot in self.connections.slots.iter_mut() {
+            if matches!(*slot, Slot::Pending { binding, .. } if binding == self.id) {
+                *slot = Slot::Expired;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rrp::auth::hash_token;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+    use tonic::Code;
+
+    use super::*;
+    use crate::auth::Auth;
+
+    const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Alice's token is "aa" and bob's is "bb"
+    fn clients() -> (Client, Client) {
+        let auth = Auth::from_toml(&format!(
+            "[alice]\nhashed_token = \"{}\"\n[bob]\nhashed_token = \"{}\"\n",
+            hash_token("aa").unwrap(),
+            hash_token("bb").unwrap(),
+        ))
+        .unwrap();
+        (auth.by_token("aa").unwrap(), auth.by_token("bb").unwrap())
+    }
+
+    fn connections() -> &'static PendingConnections {
+        Box::leak(Box::new(PendingConnections::new(ACCEPT_TIMEOUT)))
+    }
+
+    // A visitor along with the other end of its connection
+    fn visitor() -> (Visitor, DuplexStream) {
+        let (stream, remote) = duplex(64);
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        (Visitor::private(stream, addr, addr), remote)
+    }
+
+    fn claim(connections: &'static PendingConnections, client: &Client, id: u64) -> Code {
+        match connections.claim(client, id) {
+            Ok(_) => Code::Ok,
+            Err(status) => status.code(),
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_connections_once() {
+        let (alice, _) = clients();
+        let connections = connections();
+        let binding = connections.open(&alice, Limits::default());
+
+        let id = binding.insert(visitor().0);
+        assert_eq!(claim(connections, &alice, id), Code::Ok);
+        assert_eq!(claim(connections, &alice, id), Code::FailedPrecondition);
+        assert_eq!(claim(connections, &alice, id + 1), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn doesnt_let_other_clients_claim_connections() {
+        let (alice, bob) = clients();
+        let connections = connections();
+        let binding = connections.open(&alice, Limits::default());
+
+        let id = binding.insert(visitor().0);
+        assert_eq!(claim(connections, &bob, id), Code::PermissionDenied);
+        // the connection is still there for its owner
+        assert_eq!(claim(connections, &alice, id), Code::Ok);
+    }
+
+    #[tokio::test]
+    async fn closes_the_connections_of_a_dropped_binding() {
+        let (alice, _) = clients();
+        let connections = connections();
+        let binding = connections.open(&alice, Limits::default());
+        let other = connections.open(&alice, Limits::default());
+
+        let (first, mut remote) = visitor();
+        let (second, _other_remote) = visitor();
+        let id = binding.insert(first);
+        let other_id = other.insert(second);
+        drop(binding);
+
+        assert_eq!(remote.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(claim(connections, &alice, id), Code::DeadlineExceeded);
+        // the connections of other bindings are left alone
+        assert_eq!(claim(connections, &alice, other_id), Code::Ok);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_connections_that_arent_claimed_in_time() {
+        let (alice, _) = clients();
+        let connections = connections();
+        let binding = connections.open(&alice, Limits::default());
+
+        let (visitor, mut remote) = visitor();
+        let id = binding.insert(visitor);
+        tokio::time::sleep(ACCEPT_TIMEOUT + Duration::from_secs(1)).await;
+
+        assert_eq!(remote.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(claim(connections, &alice, id), Code::DeadlineExceeded);
+
+        // the expired connection is forgotten after a while
+        tokio::time::sleep(SETTLED_CONNECTION_TTL).await;
+        assert_eq!(claim(connections, &alice, id), Code::NotFound);
+    }
+}
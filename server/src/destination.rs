@@ -0,0 +1,263 @@
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
+
+use rrp::cidr::Cidr;
+use serde::{Deserialize, Serialize};
+
+use crate::ports::PortRange;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid destination: {0}, expected \"host:port\"")]
+    InvalidDestination(String),
+}
+
+// A destination the server is allowed to connect to on behalf of a client
+//
+// written as "host:port", where the host is either a hostname, a wildcard
+// subdomain ("*.internal"), an ip range ("10.0.0.0/8", "[2001:db8::/32]")
+// or "*" for any host, and the port is either a number, a range of ports ("8000-8100")
+// or "*" for any port
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DestinationRule {
+    host: HostPattern,
+    // None matches any port
+    port: Option<PortRange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Name(String),
+    // Matches the subdomains of a domain, but not the domain itself
+    Subdomain(String),
+    Network(Cidr),
+}
+
+impl DestinationRule {
+    // Checks whether the rule allows connecting to an address that the host was resolved to
+    pub fn allows(&self, host: &str, addr: SocketAddr) -> bool {
+        if self.port.is_some_and(|port| !port.contains(addr.port())) {
+            return false;
+        }
+
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Name(name) => host == *name,
+            HostPattern::Subdomain(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|label| label.ends_with('.')),
+            HostPattern::Network(network) => network.contains(addr.ip()),
+        }
+    }
+}
+
+impl FromStr for DestinationRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidDestination(s.to_string());
+
+        let (host, port) = s.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| invalid())?),
+        };
+
+        // ipv6 ranges are bracketed, so their colons don't get mixed up with the port's
+        let host = host.to_ascii_lowercase();
+        let bracketed = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'));
+        let host = if let Some(network) = bracketed {
+            HostPattern::Network(network.parse().map_err(|_| invalid())?)
+        } else if host == "*" {
+            HostPattern::Any
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Subdomain(domain.to_string())
+        } else if let Ok(network) = host.parse() {
+            HostPattern::Network(network)
+        } else if !host.is_empty() && !host.contains(['*', '/', ':']) {
+            HostPattern::Name(host)
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Self { host, port })
+    }
+}
+
+impl TryFrom<String> for DestinationRule {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<DestinationRule> for String {
+    fn from(rule: DestinationRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl Display for DestinationRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            HostPattern::Any => write!(f, "*")?,
+            HostPattern::Name(name) => write!(f, "{}", name)?,
+            HostPattern::Subdomain(domain) => write!(f, "*.{}", domain)?,
+            HostPattern::Network(network) if network.to_string().contains(':') => {
+                write!(f, "[{}]", network)?
+            }
+            HostPattern::Network(network) => write!(f, "{}", network)?,
+        }
+
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => write!(f, ":*"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> DestinationRule {
+        s.parse().unwrap()
+    }
+
+    fn allows(destination: &str, host: &str, addr: &str) -> bool {
+        rule(destination).allows(host, addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_rules() {
+        for s in [
+            "db.internal:5432",
+            "*.example.com:443",
+            "10.0.0.0/8:*",
+            "10.0.0.1/32:80",
+            "[2001:db8::/32]:443",
+            "db.internal:8000-8100",
+            "*:*",
+        ] {
+            assert_eq!(rule(s).to_string(), s);
+        }
+        assert_eq!(rule(" DB.Internal:5432 ").to_string(), "db.internal:5432");
+        // a plain address is a range of its own
+        assert_eq!(rule("10.0.0.1:80"), rule("10.0.0.1/32:80"));
+        assert_eq!(rule("[::1]:80"), rule("[::1/128]:80"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for s in [
+            "",
+            "db.internal",
+            "db.internal:",
+            ":5432",
+            "db.internal:0",
+            "db.internal:65536",
+            "db.internal:http",
+            "db.internal:9100-9000",
+            "db.*.internal:5432",
+            "10.0.0.0/33:80",
+            "[2001:db8::/32:443",
+            "[db.internal]:5432",
+        ] {
+            assert!(s.parse::<DestinationRule>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn matches_hostnames() {
+        assert!(allows("db.internal:5432", "db.internal", "10.0.0.1:5432"));
+        assert!(allows("db.internal:5432", "DB.Internal.", "10.0.0.1:5432"));
+        assert!(!allows("db.internal:5432", "db2.internal", "10.0.0.1:5432"));
+        assert!(!allows(
+            "db.internal:5432",
+            "a.db.internal",
+            "10.0.0.1:5432"
+        ));
+        // the rule is about the name, not about whatever the name resolved to
+        assert!(!allows("db.internal:5432", "10.0.0.1", "10.0.0.1:5432"));
+
+        assert!(allows(
+            "*.example.com:443",
+            "api.example.com",
+            "1.2.3.4:443"
+        ));
+        assert!(allows(
+            "*.example.com:443",
+            "a.b.example.com",
+            "1.2.3.4:443"
+        ));
+        assert!(!allows("*.example.com:443", "example.com", "1.2.3.4:443"));
+        assert!(!allows(
+            "*.example.com:443",
+            "badexample.com",
+            "1.2.3.4:443"
+        ));
+
+        assert!(allows("*:*", "anything", "1.2.3.4:1"));
+    }
+
+    #[test]
+    fn matches_ip_literals_and_ranges() {
+        // ip rules are matched against the address the host resolved to, whatever the host's name
+        assert!(allows("10.0.0.1:80", "10.0.0.1", "10.0.0.1:80"));
+        assert!(allows("10.0.0.1:80", "db.internal", "10.0.0.1:80"));
+        assert!(!allows("10.0.0.1:80", "10.0.0.2", "10.0.0.2:80"));
+
+        assert!(allows("10.0.0.0/8:*", "db.internal", "10.1.2.3:5432"));
+        assert!(!allows("10.0.0.0/8:*", "db.internal", "192.168.0.1:5432"));
+        assert!(allows(
+            "[2001:db8::/32]:443",
+            "v6.internal",
+            "[2001:db8::1]:443"
+        ));
+        assert!(!allows(
+            "[2001:db8::/32]:443",
+            "v6.internal",
+            "[2001:db9::1]:443"
+        ));
+        assert!(!allows(
+            "[2001:db8::/32]:443",
+            "v4.internal",
+            "10.0.0.1:443"
+        ));
+    }
+
+    #[test]
+    fn matches_ports() {
+        assert!(allows("db.internal:5432", "db.internal", "10.0.0.1:5432"));
+        assert!(!allows("db.internal:5432", "db.internal", "10.0.0.1:5433"));
+
+        assert!(allows(
+            "db.internal:8000-8100",
+            "db.internal",
+            "10.0.0.1:8000"
+        ));
+        assert!(allows(
+            "db.internal:8000-8100",
+            "db.internal",
+            "10.0.0.1:8100"
+        ));
+        assert!(!allows(
+            "db.internal:8000-8100",
+            "db.internal",
+            "10.0.0.1:7999"
+        ));
+        assert!(!allows(
+            "db.internal:8000-8100",
+            "db.internal",
+            "10.0.0.1:8101"
+        ));
+
+        assert!(allows("db.internal:*", "db.internal", "10.0.0.1:1"));
+        assert!(allows("db.internal:*", "db.internal", "10.0.0.1:65535"));
+    }
+}
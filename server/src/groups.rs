@@ -0,0 +1,578 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use dashmap::DashMap;
+use rrp::grpc::LoadBalancing;
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::sleep,
+};
+use tonic::Status;
+
+use crate::{
+    auth::{Auth, Client},
+    config::Config,
+    ports::PortPolicy,
+    utils::ACCEPT_ERROR_BACKOFF,
+    visitor::{Visitor, VisitorFilter},
+};
+
+// The amount of routed visitors we'll buffer for a single member
+// before rejecting new ones
+const MEMBER_BACK_PRESSURE: usize = 64;
+
+// Held by a visitor for as long as its connection is open,
+// so the group knows how many connections each of its members is handling
+#[derive(Clone, Default)]
+pub struct Load(Arc<()>);
+
+impl Load {
+    fn connections(&self) -> usize {
+        // one reference is held by the member itself
+        Arc::strong_count(&self.0) - 1
+    }
+}
+
+// The bindings that share a port or a hostname
+//
+// every visitor is routed to one of the members by the group's strategy,
+// a group without a strategy belongs to a single binding and can't be joined
+pub struct Group {
+    // The identifier of the client whose bindings may join the group,
+    // along with the bindings of the clients in its load balancing group
+    owner: String,
+    owner_group: Option<String>,
+    strategy: Option<LoadBalancing>,
+
+    last_id: AtomicU64,
+    // Where the next round starts, so visitors are spread evenly between equal members
+    cursor: AtomicUsize,
+    members: Mutex<Vec<Member>>,
+}
+
+struct Member {
+    id: u64,
+    filter: VisitorFilter,
+    load: Load,
+    visitors: mpsc::Sender<Visitor>,
+}
+
+// A binding's place in its group, the binding leaves the group once it's dropped
+pub struct Membership {
+    group: Arc<Group>,
+    id: u64,
+    visitors: mpsc::Receiver<Visitor>,
+}
+
+impl Group {
+    pub fn new(owner: &Client, strategy: Option<LoadBalancing>) -> Arc<Self> {
+        Arc::new(Self {
+            owner: owner.identifier().to_string(),
+            owner_group: owner.load_balancing_group().map(str::to_string),
+            strategy,
+            last_id: AtomicU64::default(),
+            cursor: AtomicUsize::default(),
+            members: Mutex::default(),
+        })
+    }
+
+    // Makes sure a binding of the client may join the group with the strategy,
+    // the name describes what the group shares in the returned error
+    #[allow(clippy::result_large_err)]
+    pub fn admits(
+        &self,
+        client: &Client,
+        strategy: Option<LoadBalancing>,
+        name: impl Display,
+    ) -> Result<(), Status> {
+        let shares = self.owner == client.identifier()
+            || (self.owner_group.is_some()
+                && self.owner_group.as_deref() == client.load_balancing_group());
+        if self.strategy.is_none() || strategy.is_none() || !shares {
+            return Err(Status::already_exists(format!("{} is already bound", name)));
+        }
+        if self.strategy != strategy {
+            return Err(Status::failed_precondition(format!(
+                "{} is already load balanced with another strategy",
+                name
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Adds a binding to the group, it only receives the visitors its filter lets through
+    pub fn join(self: &Arc<Self>, filter: VisitorFilter) -> Membership {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = mpsc::channel(MEMBER_BACK_PRESSURE);
+        self.members.lock().unwrap().push(Member {
+            id,
+            filter,
+            load: Load::default(),
+            visitors: tx,
+        });
+
+        Membership {
+            group: self.clone(),
+            id,
+            visitors: rx,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.lock().unwrap().is_empty()
+    }
+
+    // Routes a visitor to one of the members,
+    // the visitor is handed back if none of them can take it
+    pub fn dispatch(&self, mut visitor: Visitor) -> Option<Visitor> {
+        let ip = visitor.peer_addr.ip();
+        let members = self.members.lock().unwrap();
+        let candidates: Vec<_> = members
+            .iter()
+            .filter(|member| member.filter.allows(ip))
+            .collect();
+        if candidates.is_empty() {
+            return Some(visitor);
+        }
+
+        let offset = self.cursor.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        let mut round = candidates
+            .iter()
+            .cycle()
+            .skip(offset)
+            .take(candidates.len());
+        let member = match self.strategy {
+            None | Some(LoadBalancing::RoundRobin) => round.next(),
+            Some(LoadBalancing::LeastConnections) => {
+                round.min_by_key(|member| member.load.connections())
+            }
+            // rendezvous hashing, only the visitors of a member that left are moved to the others
+            Some(LoadBalancing::SourceIp) => candidates
+                .iter()
+                .max_by_key(|member| affinity(ip, member.id)),
+        }
+        .expect("there is at least one candidate");
+
+        visitor.load = Some(member.load.clone());
+        match member.visitors.try_send(visitor) {
+            Ok(()) => None,
+            Err(TrySendError::Full(visitor) | TrySendError::Closed(visitor)) => Some(visitor),
+        }
+    }
+
+    fn leave(&self, id: u64) {
+        self.members
+            .lock()
+            .unwrap()
+            .retain(|member| member.id != id);
+    }
+}
+
+// How strongly a visitor's address is tied to a member
+fn affinity(ip: IpAddr, member: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (ip, member).hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Membership {
+    // Waits for the next visitor that was routed to this binding
+    pub async fn next(&mut self) -> Option<Visitor> {
+        self.visitors.recv().await
+    }
+
+    // Stops routing visitors to this binding,
+    // the visitors that were routed to it but not received yet are routed to the other members
+    pub fn leave(&mut self) {
+        self.group.leave(self.id);
+
+        self.visitors.close();
+        while let Ok(visitor) = self.visitors.try_recv() {
+            // the visitors that none of the other members can take are closed
+            let _ = self.group.dispatch(visitor);
+        }
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
+
+// The tcp ports that are bound by groups, so more bindings can join the load balanced ones
+pub struct PortGroups {
+    config: &'static Config,
+    policy: PortPolicy,
+    ports: DashMap<SocketAddr, Weak<PortGroup>>,
+}
+
+// A tcp port and the group of bindings that it's shared by,
+// the port is released once every member let go of it
+pub struct PortGroup {
+    group: Arc<Group>,
+    addr: SocketAddr,
+    // Accepts the port's visitors and routes them to the group's members,
+    // so the visitors don't depend on the member that happened to accept them
+    serving: JoinHandle<()>,
+    registry: &'static PortGroups,
+}
+
+impl PortGroups {
+    pub fn new(config: &'static Config, auth: &'static Auth) -> Self {
+        Self {
+            config,
+            policy: PortPolicy::new(config, auth),
+            ports: DashMap::new(),
+        }
+    }
+
+    // Joins the group that already holds the port on the address,
+    // or binds a new port for a new group if there is no such group
+    //
+    // a port that is held by a group the binding can't join is reported as already bound,
+    // and the client still needs to be allowed to expose the port to join its group
+    #[allow(clippy::result_large_err)]
+    pub fn bind(
+        &'static self,
+        client: &Client,
+        ip: IpAddr,
+        port: Option<u16>,
+        strategy: Option<LoadBalancing>,
+        filter: VisitorFilter,
+        bind: impl FnOnce() -> Result<TcpListener, Status>,
+    ) -> Result<(Arc<PortGroup>, Membership), Status> {
+        let shared = port.and_then(|port| self.ports.get(&(ip, port).into())?.upgrade());
+        if let Some(shared) = shared {
+            let port = shared.addr.port();
+            if !self.policy.allows(client, port) {
+                return Err(Status::permission_denied(format!(
+                    "exposing port {} is not allowed",
+                    port
+                )));
+            }
+            shared
+                .group
+                .admits(client, strategy, format!("the port: {}", port))?;
+            let membership = shared.group.join(filter);
+
+            return Ok((shared, membership));
+        }
+
+        // two bindings that start a group at the same time race for the port,
+        // the one that loses gets an error just like for any other taken port
+        let listener = bind()?;
+        let addr = listener.local_addr()?;
+        let group = Group::new(client, strategy);
+        let membership = group.join(filter);
+        let shared = Arc::new(PortGroup {
+            serving: tokio::spawn(serve(listener, group.clone(), self.config)),
+            group,
+            addr,
+            registry: self,
+        });
+        self.ports.insert(addr, Arc::downgrade(&shared));
+
+        Ok((shared, membership))
+    }
+}
+
+impl PortGroup {
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+}
+
+// Accepts the visitors of a port until the port is released
+async fn serve(listener: TcpListener, group: Arc<Group>, config: &'static Config) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("failed to accept a connection on an exposed port: {}", err);
+                sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        // a visitor might need to send a PROXY protocol header before it's ready,
+        // a slow visitor shouldn't hold back the others
+        let group = group.clone();
+        tokio::spawn(async move {
+            // the visitors that went away or sent an invalid header are dropped,
+            // and so are the ones that none of the members let through
+            if let Ok(visitor) = Visitor::accept(stream, peer_addr, config).await {
+                let _ = group.dispatch(visitor);
+            }
+        });
+    }
+}
+
+impl Drop for PortGroup {
+    fn drop(&mut self) {
+        // dropping the listener closes the port
+        self.serving.abort();
+        self.registry
+            .ports
+            .remove_if(&self.addr, |_, shared| shared.strong_count() == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rrp::auth::hash_token;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+    use tonic::Code;
+
+    use super::*;
+    use crate::utils;
+
+    // Port groups whose clients all share a load balancing group,
+    // alice's token is "aa", bob's is "bb" and carol's is "cc"
+    fn port_groups(alice: &str, bob: &str, carol: &str) -> (&'static PortGroups, [Client; 3]) {
+        let clients = [
+            ("alice", "aa", alice),
+            ("bob", "bb", bob),
+            ("carol", "cc", carol),
+        ];
+        let auth = clients
+            .iter()
+            .map(|(name, token, entry)| {
+                format!(
+                    "[{}]\nhashed_token = \"{}\"\nload_balancing_group = \"team\"\n{}\n",
+                    name,
+                    hash_token(token).unwrap(),
+                    entry
+                )
+            })
+            .collect::<String>();
+        let auth: &'static Auth = Box::leak(Box::new(Auth::from_toml(&auth).unwrap()));
+        let config = Box::leak(Box::new(Config::from_toml("").unwrap()));
+
+        let groups = Box::leak(Box::new(PortGroups::new(config, auth)));
+        (
+            groups,
+            clients.map(|(_, token, _)| auth.by_token(token).unwrap()),
+        )
+    }
+
+    // A free port on the loopback address, along with its listener
+    fn listener() -> (TcpListener, u16) {
+        let listener = utils::bind_tcp(([127, 0, 0, 1], 0).into()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    // Binds the port in a group of the client,
+    // the listener is only used if the client starts a new group
+    #[allow(clippy::result_large_err)]
+    fn bind(
+        groups: &'static PortGroups,
+        client: &Client,
+        port: u16,
+        listener: Option<TcpListener>,
+    ) -> Result<(Arc<PortGroup>, Membership), Status> {
+        groups.bind(
+            client,
+            [127, 0, 0, 1].into(),
+            Some(port),
+            Some(LoadBalancing::RoundRobin),
+            VisitorFilter::default(),
+            || Ok(listener.expect("the port is already bound by the group")),
+        )
+    }
+
+    #[tokio::test]
+    async fn joins_ports_by_the_policy() {
+        let (listener, port) = listener();
+        let (groups, [alice, bob, carol]) = port_groups("", "allowed_ports = [\"1024\"]", "");
+
+        let _alice = bind(groups, &alice, port, Some(listener)).unwrap();
+
+        // bob shares the group, but isn't allowed to expose the port
+        assert_eq!(
+            bind(groups, &bob, port, None)
+                .err()
+                .map(|status| status.code()),
+            Some(Code::PermissionDenied)
+        );
+        let (shared, _carol) = bind(groups, &carol, port, None).unwrap();
+        assert_eq!(shared.port(), port);
+    }
+
+    #[tokio::test]
+    async fn doesnt_join_ports_reserved_by_others() {
+        let (listener, port) = listener();
+        let (groups, [alice, bob, _]) =
+            port_groups(&format!("reserved_ports = [\"{}\"]", port), "", "");
+
+        let _alice = bind(groups, &alice, port, Some(listener)).unwrap();
+
+        assert_eq!(
+            bind(groups, &bob, port, None)
+                .err()
+                .map(|status| status.code()),
+            Some(Code::PermissionDenied)
+        );
+    }
+
+    // A group of alice's bindings
+    fn group(strategy: LoadBalancing) -> Arc<Group> {
+        let (_, [alice, _, _]) = port_groups("", "", "");
+        Group::new(&alice, Some(strategy))
+    }
+
+    // Routes a new visitor from the ip, returns the other end of its connection
+    fn dispatch(group: &Group, ip: [u8; 4]) -> DuplexStream {
+        let (stream, remote) = duplex(64);
+        let visitor = Visitor::private(stream, (ip, 1234).into(), ([127, 0, 0, 1], 80).into());
+        assert!(
+            group.dispatch(visitor).is_none(),
+            "the visitor wasn't routed"
+        );
+        remote
+    }
+
+    // The member that the last visitor was routed to, along with the visitor
+    fn routed(members: &mut [Membership]) -> (usize, Visitor) {
+        let mut routed = members
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, member)| Some((index, member.visitors.try_recv().ok()?)));
+        let first = routed.next().expect("the visitor was routed to a member");
+        assert!(routed.next().is_none(), "the visitor was routed twice");
+        first
+    }
+
+    #[test]
+    fn routes_round_robin() {
+        let group = group(LoadBalancing::RoundRobin);
+        let mut members: Vec<_> = (0..3)
+            .map(|_| group.join(VisitorFilter::default()))
+            .collect();
+
+        let order: Vec<_> = (0..6)
+            .map(|_| {
+                dispatch(&group, [10, 0, 0, 1]);
+                routed(&mut members).0
+            })
+            .collect();
+        assert_eq!(order, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn routes_to_the_least_connections() {
+        let group = group(LoadBalancing::LeastConnections);
+        let mut members: Vec<_> = (0..2)
+            .map(|_| group.join(VisitorFilter::default()))
+            .collect();
+
+        let mut visitors: Vec<_> = (0..3)
+            .map(|_| {
+                dispatch(&group, [10, 0, 0, 1]);
+                routed(&mut members)
+            })
+            .collect();
+        let order: Vec<_> = visitors.iter().map(|(index, _)| *index).collect();
+        assert_eq!(order, [0, 1, 0]);
+
+        // the second member's connection is closed, so it's the least busy by far
+        visitors.retain(|(index, _)| *index == 0);
+        for _ in 0..2 {
+            dispatch(&group, [10, 0, 0, 1]);
+            let (index, visitor) = routed(&mut members);
+            assert_eq!(index, 1);
+            visitors.push((index, visitor));
+        }
+    }
+
+    #[test]
+    fn routes_by_source_ip() {
+        let group = group(LoadBalancing::SourceIp);
+        let mut members: Vec<_> = (0..3)
+            .map(|_| group.join(VisitorFilter::default()))
+            .collect();
+        let route = |members: &mut Vec<Membership>, ip: u8| {
+            dispatch(&group, [10, 0, 0, ip]);
+            routed(members).0
+        };
+
+        let routes: Vec<_> = (1..=30).map(|ip| route(&mut members, ip)).collect();
+        assert!((0..3).all(|index| routes.contains(&index)));
+        for ip in 1..=30 {
+            assert_eq!(route(&mut members, ip), routes[ip as usize - 1]);
+        }
+
+        // only the visitors of the member that left are moved
+        members.remove(1);
+        for ip in 1..=30 {
+            match routes[ip as usize - 1] {
+                0 => assert_eq!(route(&mut members, ip), 0),
+                2 => assert_eq!(route(&mut members, ip), 1),
+                _ => {
+                    route(&mut members, ip);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn routes_by_the_filters_of_the_members() {
+        let group = group(LoadBalancing::RoundRobin);
+        let filter = |allow: &str| VisitorFilter::parse(&[allow.to_string()], &[]).unwrap();
+        let mut members = [
+            group.join(filter("10.0.0.0/8")),
+            group.join(filter("192.168.0.0/16")),
+        ];
+
+        for _ in 0..3 {
+            dispatch(&group, [10, 0, 0, 1]);
+            assert_eq!(routed(&mut members).0, 0);
+            dispatch(&group, [192, 168, 0, 1]);
+            assert_eq!(routed(&mut members).0, 1);
+        }
+
+        // a visitor that none of the members let through is handed back
+        let (stream, _remote) = duplex(64);
+        let addr = ([172, 16, 0, 1], 1234).into();
+        assert!(group
+            .dispatch(Visitor::private(stream, addr, addr))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn moves_queued_visitors_of_a_member_that_left() {
+        let group = group(LoadBalancing::RoundRobin);
+        let first = group.join(VisitorFilter::default());
+        let mut second = group.join(VisitorFilter::default());
+
+        let _queued: Vec<_> = (0..4).map(|_| dispatch(&group, [10, 0, 0, 1])).collect();
+        drop(first);
+        assert!(!group.is_empty());
+
+        let mut moved = 0;
+        while second.visitors.try_recv().is_ok() {
+            moved += 1;
+        }
+        assert_eq!(moved, 4);
+
+        // the queued visitors of the last member are closed, nobody is left to take them
+        let mut remotes: Vec<_> = (0..2).map(|_| dispatch(&group, [10, 0, 0, 1])).collect();
+        drop(second);
+        assert!(group.is_empty());
+        for remote in &mut remotes {
+            assert_eq!(remote.read(&mut [0; 1]).await.unwrap(), 0);
+        }
+    }
+}
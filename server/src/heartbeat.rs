@@ -0,0 +1,87 @@
+use rrp::grpc::Heartbeat;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tonic::Status;
+
+use crate::config::Config;
+
+// The heartbeats of a single bind stream
+//
+// a heartbeat is sent every interval and the client echoes it back,
+// the binding is torn down once too many of them went unanswered
+pub struct Heartbeats {
+    interval: Interval,
+    misses: u32,
+    // The client may consider the server dead after this many milliseconds of silence
+    timeout: u64,
+    sent: u64,
+    acked: u64,
+}
+
+impl Heartbeats {
+    pub fn new(config: &Config) -> Self {
+        // the metadata was just sent, so the first heartbeat isn't due yet
+        let mut interval = time::interval_at(
+            Instant::now() + config.heartbeat_interval,
+            config.heartbeat_interval,
+        );
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            interval,
+            misses: config.heartbeat_misses,
+            timeout: (config.heartbeat_interval * config.heartbeat_misses).as_millis() as u64,
+            sent: 0,
+            acked: 0,
+        }
+    }
+
+    // Waits for the next heartbeat that needs to be sent,
+    // fails once the client has missed too many of them
+    pub async fn tick(&mut self) -> Result<Heartbeat, Status> {
+        self.interval.tick().await;
+        if self.sent - self.acked >= self.misses as u64 {
+            return Err(Status::deadline_exceeded(format!(
+                "the client missed {} heartbeats in a row",
+                self.misses
+            )));
+        }
+
+        self.sent += 1;
+        Ok(Heartbeat {
+            sequence: self.sent,
+            timeout: self.timeout,
+        })
+    }
+
+    // Records a heartbeat the client echoed back
+    #[allow(clippy::result_large_err)]
+    pub fn ack(&mut self, heartbeat: &Heartbeat) -> Result<(), Status> {
+        if heartbeat.sequence > self.sent {
+            return Err(Status::invalid_argument(format!(
+                "the heartbeat: {} was never sent",
+                heartbeat.sequence
+            )));
+        }
+
+        self.acked = self.acked.max(heartbeat.sequence);
+        Ok(())
+    }
+
+    // Records a message the client sent after the metadata, only heartbeats are expected
+    //
+    // returns false once the client has closed its end of the stream
+    #[allow(clippy::result_large_err)]
+    pub fn receive(
+        &mut self,
+        msg: Option<Result<Option<Heartbeat>, Status>>,
+    ) -> Result<bool, Status> {
+        match msg {
+            Some(Ok(Some(heartbeat))) => self.ack(&heartbeat).map(|_| true),
+            Some(Ok(None)) => Err(Status::invalid_argument(
+                "all messages, except the first one, need to contain a heartbeat",
+            )),
+            Some(Err(err)) => Err(err),
+            None => Ok(false),
+        }
+    }
+}
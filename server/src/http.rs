@@ -0,0 +1,345 @@
+use std::io::{Error, ErrorKind};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tonic::Status;
+
+use crate::visitor::Visitor;
+
+// The largest request head we're willing to buffer while looking for the host header
+const MAX_REQUEST_HEAD_SIZE: usize = 16 * 1024;
+
+const HEAD_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+pub const UNAUTHORIZED: &str = "401 Unauthorized";
+pub const NOT_FOUND: &str = "404 Not Found";
+pub const SERVICE_UNAVAILABLE: &str = "503 Service Unavailable";
+
+// Reads the head of the visitor's first http request (the request line and the headers)
+//
+// everything that is read is kept in the visitor's prefix, which might include the beginning of the body
+pub async fn read_request_head(visitor: &mut Visitor) -> std::io::Result<()> {
+    while find_head_end(&visitor.prefix).is_none() {
+        if visitor.prefix.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "request head is too large",
+            ));
+        }
+
+        visitor.read_more().await?;
+    }
+
+    Ok(())
+}
+
+// Extracts the hostname from the host header of a request head
+//
+// the hostname is returned lowercased and without a port
+pub fn parse_host(head: &[u8]) -> Option<String> {
+    let host = parse_header(head, "host")?;
+
+    // strip the port, ipv6 literals are enclosed in brackets
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    };
+
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+// Extracts the value of the first header with this name from a request head
+fn parse_header<'a>(head: &'a [u8], name: &str) -> Option<&'a str> {
+    let head = std::str::from_utf8(&head[..find_head_end(head)?]).ok()?;
+
+    head
+        // skip the request line
+        .split("\r\n")
+        .skip(1)
+        .find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header
+                .trim()
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim())
+        })
+}
+
+// The credentials the visitors of an http binding need to present before they're routed,
+// checked against the authorization header of the connection's first request
+//
+// only the first request of a connection is seen, so the requests of bindings
+// that require credentials are made to close the connection once they're answered
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct EdgeAuth {
+    // "user:password"
+    basic: Option<String>,
+    bearer: Option<String>,
+}
+
+impl EdgeAuth {
+    #[allow(clippy::result_large_err)]
+    pub fn new(basic: Option<String>, bearer: Option<String>) -> Result<Self, Status> {
+        if basic.as_ref().is_some_and(|basic| !basic.contains(':')) {
+            return Err(Status::invalid_argument(
+                "basic auth credentials need to be in the form of user:password",
+            ));
+        }
+
+        Ok(Self { basic, bearer })
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.basic.is_some() || self.bearer.is_some()
+    }
+
+    // Whether the request head carries one of the credentials
+    pub fn authorizes(&self, head: &[u8]) -> bool {
+        if !self.is_required() {
+            return true;
+        }
+
+        let Some((scheme, credentials)) =
+            parse_header(head, "authorization").and_then(|value| value.split_once(' '))
+        else {
+            return false;
+        };
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = BASE64.decode(credentials).unwrap_or_default();
+            return self
+                .basic
+                .as_ref()
+                .is_some_and(|basic| constant_time_eq(basic.as_bytes(), &decoded));
+        }
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return self
+                .bearer
+                .as_ref()
+                .is_some_and(|bearer| constant_time_eq(bearer.as_bytes(), credentials.as_bytes()));
+        }
+
+        false
+    }
+
+    // The www-authenticate headers that tell the visitor which credentials to present
+    fn challenges(&self) -> String {
+        let mut challenges = String::new();
+        if self.basic.is_some() {
+            challenges.push_str("WWW-Authenticate: Basic realm=\"rrp\", charset=\"UTF-8\"\r\n");
+        }
+        if self.bearer.is_some() {
+            challenges.push_str("WWW-Authenticate: Bearer realm=\"rrp\"\r\n");
+        }
+
+        challenges
+    }
+}
+
+// Rewrites the head of the request at the beginning of the data to ask the server
+// to close the connection once it answered it, so no other request can follow on the connection
+//
+// the data after the head (the beginning of the body) is kept as is
+pub fn close_after_response(data: &mut Vec<u8>) {
+    let Some(end) = find_head_end(data) else {
+        return;
+    };
+
+    let mut lines = lines(&data[..end]);
+    let mut rewritten = lines.next().unwrap_or_default().to_vec();
+    for line in lines {
+        let header = line
+            .split(|byte| *byte == b':')
+            .next()
+            .unwrap_or_default()
+            .trim_ascii();
+        if header.eq_ignore_ascii_case(b"connection") || header.eq_ignore_ascii_case(b"keep-alive")
+        {
+            continue;
+        }
+
+        rewritten.extend_from_slice(b"\r\n");
+        rewritten.extend_from_slice(line);
+    }
+    rewritten.extend_from_slice(b"\r\nConnection: close");
+
+    data.splice(..end, rewritten);
+}
+
+// Splits the head of a request into its lines, without their line breaks
+fn lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(head);
+    std::iter::from_fn(move || {
+        let head = rest?;
+        match head.windows(2).position(|window| window == b"\r\n") {
+            Some(at) => {
+                rest = Some(&head[at + 2..]);
+                Some(&head[..at])
+            }
+            None => {
+                rest = None;
+                Some(head)
+            }
+        }
+    })
+}
+
+// Compares the secrets without leaking how much of them matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Writes a minimal response that closes the connection
+pub async fn respond(stream: &mut (impl AsyncWrite + Unpin), status: &str) -> std::io::Result<()> {
+    respond_with_headers(stream, status, "").await
+}
+
+// Writes a minimal response that asks the visitor for the binding's credentials
+pub async fn respond_unauthorized(
+    stream: &mut (impl AsyncWrite + Unpin),
+    auth: &EdgeAuth,
+) -> std::io::Result<()> {
+    respond_with_headers(stream, UNAUTHORIZED, &auth.challenges()).await
+}
+
+// Writes a minimal response with extra headers, every header needs to end with a line break
+async fn respond_with_headers(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        status.len(),
+        status
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(HEAD_TERMINATOR.len())
+        .position(|window| window == HEAD_TERMINATOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> EdgeAuth {
+        EdgeAuth::new(Some("user:secret".to_string()), Some("token".to_string())).unwrap()
+    }
+
+    fn request(headers: &str) -> Vec<u8> {
+        format!("GET / HTTP/1.1\r\n{}\r\nbody", headers).into_bytes()
+    }
+
+    #[test]
+    fn parses_headers_case_insensitively() {
+        let head = request("HOST: Example.com:8080\r\nx-Custom :  value \r\n");
+        assert_eq!(parse_header(&head, "host"), Some("Example.com:8080"));
+        assert_eq!(parse_header(&head, "X-CUSTOM"), Some("value"));
+        assert_eq!(parse_header(&head, "missing"), None);
+        assert_eq!(parse_host(&head).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn parses_only_complete_heads() {
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\nHost: a\r\n", "host"), None);
+        // the request line isn't a header
+        assert_eq!(parse_header(&request(""), "get / http/1.1"), None);
+    }
+
+    #[test]
+    fn strips_ports_from_hosts() {
+        assert_eq!(
+            parse_host(&request("Host: [::1]:80\r\n")).as_deref(),
+            Some("[::1]")
+        );
+        assert_eq!(
+            parse_host(&request("Host: [::1]\r\n")).as_deref(),
+            Some("[::1]")
+        );
+        assert_eq!(
+            parse_host(&request("Host: example.com.\r\n")).as_deref(),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn requires_a_colon_in_basic_credentials() {
+        assert!(EdgeAuth::new(Some("user".to_string()), None).is_err());
+        assert!(EdgeAuth::new(Some("user:".to_string()), None).is_ok());
+    }
+
+    #[test]
+    fn authorizes_everyone_without_credentials() {
+        assert!(EdgeAuth::default().authorizes(&request("")));
+    }
+
+    #[test]
+    fn authorizes_valid_credentials() {
+        // user:secret
+        let basic = request("Authorization: Basic dXNlcjpzZWNyZXQ=\r\n");
+        assert!(auth().authorizes(&basic));
+        let basic = request("authorization: basic   dXNlcjpzZWNyZXQ= \r\n");
+        assert!(auth().authorizes(&basic));
+
+        let bearer = request("AUTHORIZATION: BEARER token\r\n");
+        assert!(auth().authorizes(&bearer));
+    }
+
+    #[test]
+    fn rejects_invalid_credentials() {
+        for headers in [
+            "",
+            "Authorization: Basic\r\n",
+            // a wrong password
+            "Authorization: Basic dXNlcjp3cm9uZw==\r\n",
+            // bad base64
+            "Authorization: Basic dXNlcjpzZWNyZXQ=!\r\n",
+            // user:secret without the colon
+            "Authorization: Basic dXNlcnNlY3JldA==\r\n",
+            // a bad scheme
+            "Authorization: Digest dXNlcjpzZWNyZXQ=\r\n",
+            "Authorization: Bearer tok\r\n",
+            "Authorization: Bearer dXNlcjpzZWNyZXQ=\r\n",
+        ] {
+            assert!(!auth().authorizes(&request(headers)), "{:?}", headers);
+        }
+
+        // a binding that only requires one kind of credentials
+        let auth = EdgeAuth::new(None, Some("token".to_string())).unwrap();
+        assert!(!auth.authorizes(&request("Authorization: Basic dXNlcjpzZWNyZXQ=\r\n")));
+    }
+
+    #[test]
+    fn closes_the_connection_after_the_response() {
+        let mut data = request("Host: a\r\nConnection: keep-alive\r\nkeep-alive: timeout=5\r\n");
+        close_after_response(&mut data);
+        assert_eq!(
+            data,
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\nbody"
+        );
+
+        let mut data = request("Host: a\r\n");
+        close_after_response(&mut data);
+        assert_eq!(
+            data,
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\nbody"
+        );
+
+        // headers that aren't valid utf-8 are kept byte for byte
+        let mut data =
+            b"GET / HTTP/1.1\r\nX-Raw: \xff\xfe\r\nCONNECTION : upgrade\r\n\r\n\xff".to_vec();
+        close_after_response(&mut data);
+        assert_eq!(
+            data,
+            b"GET / HTTP/1.1\r\nX-Raw: \xff\xfe\r\nConnection: close\r\n\r\n\xff"
+        );
+    }
+}
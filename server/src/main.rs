@@ -1,14 +1,35 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::Context;
-use rrp::{project_dir, setup_project_dir};
-use tonic::transport::{Server, ServerTlsConfig};
+use rrp::{grpc::HostProtocol, project_dir, setup_project_dir};
+use tokio::select;
+use tonic::transport::{server::TcpIncoming, Server, ServerTlsConfig};
 
+mod announce;
 mod auth;
+mod bandwidth;
 mod config;
+mod connections;
+mod destination;
+mod groups;
+mod heartbeat;
+mod http;
+mod ports;
+mod private;
+mod quotas;
+mod relay;
+mod router;
 mod services;
+mod shutdown;
+mod sni;
 mod tls;
+mod udp;
 mod utils;
+mod visitor;
+
+// All of a client's tunneled connections are multiplexed over a single
+// long-lived http2 connection, make sure dead connections are noticed
+const HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -16,24 +37,84 @@ async fn main() -> anyhow::Result<()> {
 
     // These are shared immutable structures that are needed
     // throughout the entire lifetime of the app, leaking them has no downsides.
-    let config: &'static _ = Box::leak(Box::new(config::Config::parse()));
+    let config: &'static _ = Box::leak(Box::new(config::Config::parse()?));
     let shared_auth: &'static _ = Box::leak(Box::new(auth::Auth::load_from_file(
         project_dir().config_dir(),
     )?));
+    let shutdown: &'static _ = Box::leak(Box::<shutdown::Shutdown>::default());
+
+    let http_router = match config.http_port {
+        Some(port) => {
+            let router =
+                router::HostRouter::bind(HostProtocol::Http, port, config, shutdown).await?;
+            println!("Routing http bindings on port: {}", router.port());
+            Some(router)
+        }
+        None => None,
+    };
+    let tls_router = match config.tls_port {
+        Some(port) => {
+            let router =
+                router::HostRouter::bind(HostProtocol::Tls, port, config, shutdown).await?;
+            println!("Routing tls bindings on port: {}", router.port());
+            Some(router)
+        }
+        None => None,
+    };
 
     let addr = SocketAddr::new(config.ip, config.port);
     let identity = tls::load_server_identity(project_dir().config_dir())?;
+    // bound by hand, so the unspecified ipv6 address is dual-stack on every platform
+    let listener =
+        utils::bind_tcp(addr).with_context(|| format!("failed to listen on address: {}", addr))?;
+    let incoming = TcpIncoming::from_listener(listener, false, None)
+        .map_err(|err| anyhow::anyhow!(err))
+        .context("failed to start the server")?;
 
     println!("Server listening on address: {}", addr);
-    Server::builder()
+    let server = Server::builder()
         .tls_config(ServerTlsConfig::new().identity(identity))?
+        .http2_keepalive_interval(Some(HTTP2_KEEPALIVE_INTERVAL))
+        .http2_adaptive_window(Some(true))
         .add_service(auth::attach_auth(
             shared_auth,
-            services::ReverseProxyService::new(),
+            services::ReverseProxyService::new(
+                config,
+                shared_auth,
+                http_router,
+                tls_router,
+                shutdown,
+            ),
         ))
-        .serve(addr)
-        .await
-        .context("failed to start the server")?;
+        // once the shutdown is triggered no new clients are accepted,
+        // and the server waits for the active streams to finish
+        .serve_with_incoming_shutdown(incoming, shutdown.wait());
+
+    // the bindings end their streams once the shutdown is triggered,
+    // so only the relayed connections are left to drain
+    let drain_deadline = async {
+        shutdown.wait().await;
+        tokio::time::sleep(config.drain_timeout).await;
+    };
+
+    tokio::spawn(async move {
+        match shutdown::signal().await {
+            Ok(()) => {
+                println!(
+                    "Shutting down, draining the active connections for up to {:?}",
+                    config.drain_timeout
+                );
+                shutdown.trigger();
+            }
+            Err(err) => eprintln!("failed to listen for shutdown signals: {}", err),
+        }
+    });
+
+    select! {
+        result = server => result.context("failed to start the server")?,
+        _ = drain_deadline => println!("The drain deadline was reached, closing the remaining connections"),
+    }
+    println!("Server stopped");
 
     Ok(())
 }
@@ -0,0 +1,337 @@
+use std::{fmt::Display, io, str::FromStr};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use crate::{
+    auth::{Auth, Client},
+    config::Config,
+};
+
+// How many ports we'll try before giving up on finding a free one for a client
+const MAX_PORT_ATTEMPTS: u32 = 128;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid port range: {0}, expected \"port\" or \"start-end\"")]
+    InvalidRange(String),
+}
+
+// An inclusive range of ports, written as "9000-9100" or as a single port "9000"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub const fn new(start: u16, end: u16) -> Self {
+        Self { start, end }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    fn len(&self) -> u32 {
+        (self.end - self.start) as u32 + 1
+    }
+
+    // The ports both ranges contain, None if they don't overlap
+    fn intersect(&self, other: &PortRange) -> Option<PortRange> {
+        let range = PortRange::new(self.start.max(other.start), self.end.min(other.end));
+        (range.start <= range.end).then_some(range)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRange(s.to_string());
+
+        let (start, end) = s.trim().split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse().map_err(|_| invalid())?;
+        let end = end.trim().parse().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.start == self.end {
+            true => write!(f, "{}", self.start),
+            false => write!(f, "{}-{}", self.start, self.end),
+        }
+    }
+}
+
+// Decides which ports a client may expose
+//
+// a client may bind the ports of the server's range that it's allowed to bind,
+// except for the ones that are reserved for other clients.
+// the ports that are reserved for a client are always allowed for it
+pub struct PortPolicy {
+    config: &'static Config,
+    auth: &'static Auth,
+}
+
+impl PortPolicy {
+    pub fn new(config: &'static Config, auth: &'static Auth) -> Self {
+        Self { config, auth }
+    }
+
+    pub fn allows(&self, client: &Client, port: u16) -> bool {
+        if client.reserves_port(port) {
+            return true;
+        }
+        if self.auth.reserved_by_other(client, port) {
+            return false;
+        }
+
+        self.config.port_range.contains(port) && client.allows_port(port)
+    }
+
+    // Binds the port the client asked for,
+    // or a free port out of the ones it's allowed to bind if it didn't ask for a specific one
+    //
+    // policy violations are returned as a status, while the error of binding the port itself is left to the caller
+    #[allow(clippy::result_large_err)]
+    pub fn bind<T>(
+        &self,
+        client: &Client,
+        requested: Option<u16>,
+        mut bind: impl FnMut(u16) -> io::Result<T>,
+    ) -> Result<io::Result<T>, Status> {
+        if let Some(port) = requested.filter(|port| *port != 0) {
+            if !self.allows(client, port) {
+                return Err(Status::permission_denied(format!(
+                    "exposing port {} is not allowed",
+                    port
+                )));
+            }
+
+            return Ok(bind(port));
+        }
+
+        let ranges: Vec<_> = client
+            .allowed_ports()
+            .unwrap_or(std::slice::from_ref(&self.config.port_range))
+            .iter()
+            .filter_map(|range| range.intersect(&self.config.port_range))
+            .collect();
+        let total: u32 = ranges.iter().map(PortRange::len).sum();
+        if total == 0 {
+            return Err(Status::permission_denied(
+                "there are no ports this client is allowed to expose",
+            ));
+        }
+
+        // walk through the allowed ports from a random spot, skipping the ones that are taken
+        let offset = rand::thread_rng().gen_range(0..total);
+        for attempt in 0..total.min(MAX_PORT_ATTEMPTS) {
+            let mut index = (offset + attempt) % total;
+            let port = ranges
+                .iter()
+                .find_map(|range| match index < range.len() {
+                    true => Some(range.start + index as u16),
+                    false => {
+                        index -= range.len();
+                        None
+                    }
+                })
+                .expect("the index is within the ranges");
+
+            if self.auth.reserved_by_other(client, port) {
+                continue;
+            }
+            match bind(port) {
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+                result => return Ok(result),
+            }
+        }
+
+        Err(Status::resource_exhausted(
+            "there is no free port left that this client is allowed to expose",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rrp::auth::hash_token;
+
+    use super::*;
+
+    fn range(s: &str) -> PortRange {
+        s.parse().unwrap()
+    }
+
+    // A policy over the port range, alice's token is "aa" and bob's is "bb"
+    fn policy(port_range: &str, alice: &str, bob: &str) -> (PortPolicy, Client) {
+        let config = Config::from_toml(&format!("port_range = \"{}\"", port_range)).unwrap();
+        let auth = Auth::from_toml(&format!(
+            "[alice]\nhashed_token = \"{}\"\n{}\n[bob]\nhashed_token = \"{}\"\n{}\n",
+            hash_token("aa").unwrap(),
+            alice,
+            hash_token("bb").unwrap(),
+            bob
+        ))
+        .unwrap();
+        let alice = auth.by_token("aa").unwrap();
+
+        let policy = PortPolicy::new(Box::leak(Box::new(config)), Box::leak(Box::new(auth)));
+        (policy, alice)
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(range("9000"), PortRange::new(9000, 9000));
+        assert_eq!(range("9000-9100"), PortRange::new(9000, 9100));
+        assert_eq!(range(" 9000 - 9100 "), PortRange::new(9000, 9100));
+        assert_eq!(range("65535"), PortRange::new(65535, 65535));
+        assert_eq!(range("9000-9100").to_string(), "9000-9100");
+        assert_eq!(range("9000").to_string(), "9000");
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for s in [
+            "",
+            "0",
+            "0-100",
+            "9100-9000",
+            "9000-",
+            "-9000",
+            "a-b",
+            "65536",
+            "1-2-3",
+        ] {
+            assert!(s.parse::<PortRange>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn intersects_ranges() {
+        let wide = range("9000-9100");
+        assert_eq!(
+            wide.intersect(&range("9050-9200")),
+            Some(range("9050-9100"))
+        );
+        assert_eq!(wide.intersect(&range("8000-9000")), Some(range("9000")));
+        assert_eq!(
+            wide.intersect(&range("9010-9020")),
+            Some(range("9010-9020"))
+        );
+        assert_eq!(wide.intersect(&range("9101-9200")), None);
+        assert_eq!(range("9101-9200").len(), 100);
+    }
+
+    #[test]
+    fn binds_requested_ports_by_the_policy() {
+        let (policy, alice) = policy(
+            "9000-9100",
+            "allowed_ports = [\"9000-9010\"]\nreserved_ports = [\"80\"]",
+            "reserved_ports = [\"9005\"]",
+        );
+        let bind = |port| Ok::<_, io::Error>(port);
+
+        assert_eq!(
+            policy.bind(&alice, Some(9001), bind).unwrap().unwrap(),
+            9001
+        );
+        // reserved ports are allowed even outside of the server's range
+        assert_eq!(policy.bind(&alice, Some(80), bind).unwrap().unwrap(), 80);
+
+        for port in [9005, 9011, 8999] {
+            let err = policy.bind(&alice, Some(port), bind).unwrap_err();
+            assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        }
+    }
+
+    #[test]
+    fn walks_past_reserved_and_taken_ports() {
+        let (policy, alice) = policy("9000-9004", "", "reserved_ports = [\"9001\"]");
+
+        for _ in 0..20 {
+            let mut attempts = HashSet::new();
+            let bound = policy
+                .bind(&alice, None, |port| {
+                    attempts.insert(port);
+                    match port {
+                        9000 | 9002 | 9003 => Err(io::ErrorKind::AddrInUse.into()),
+                        port => Ok(port),
+                    }
+                })
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(bound, 9004);
+            assert!(!attempts.contains(&9001));
+        }
+    }
+
+    #[test]
+    fn walks_only_the_allowed_ports_within_the_range() {
+        let (policy, alice) = policy("9000-9100", "allowed_ports = [\"8990-9001\"]", "");
+
+        for _ in 0..20 {
+            let bound = policy
+                .bind(&alice, None, Ok::<_, io::Error>)
+                .unwrap()
+                .unwrap();
+            assert!((9000..=9001).contains(&bound));
+        }
+    }
+
+    #[test]
+    fn runs_out_of_free_ports() {
+        let (policy, alice) = policy("9000-9001", "", "");
+        let err = policy
+            .bind(&alice, None, |_| {
+                Err::<u16, _>(io::Error::from(io::ErrorKind::AddrInUse))
+            })
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn refuses_clients_without_allowed_ports() {
+        let (policy, alice) = policy("9000-9100", "allowed_ports = [\"8000-8100\"]", "");
+        let err = policy.bind(&alice, None, Ok::<_, io::Error>).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn leaves_other_bind_errors_to_the_caller() {
+        let (policy, alice) = policy("9000-9100", "", "");
+        let result = policy
+            .bind(&alice, None, |_| {
+                Err::<u16, _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            })
+            .unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+}
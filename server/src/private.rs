@@ -0,0 +1,108 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tonic::Status;
+
+use crate::{auth::Client, visitor::Visitor};
+
+// The amount of visitors we'll buffer for a single private service
+// before rejecting new ones
+const SERVICE_BACK_PRESSURE: usize = 64;
+
+// Services that aren't exposed on any public port,
+// only the clients that their owner allows can visit them through the server
+#[derive(Default)]
+pub struct PrivateServices {
+    // Maps a name -> the service
+    services: DashMap<String, PrivateService>,
+}
+
+struct PrivateService {
+    owner: Client,
+    visitors: mpsc::Sender<Visitor>,
+}
+
+// A registered private service, the name is released once the registration is dropped
+pub struct Registration {
+    services: &'static PrivateServices,
+    name: String,
+    visitors: mpsc::Receiver<Visitor>,
+}
+
+impl PrivateServices {
+    // Registers a private service, its visitors can be received from the returned registration
+    #[allow(clippy::result_large_err)]
+    pub fn register(&'static self, name: &str, owner: &Client) -> Result<Registration, Status> {
+        let name = name.trim().to_ascii_lowercase();
+        if name.is_empty() {
+            return Err(Status::invalid_argument("the service name can't be empty"));
+        }
+
+        match self.services.entry(name.clone()) {
+            Entry::Occupied(_) => Err(Status::already_exists(format!(
+                "the private service: {} is already bound",
+                name
+            ))),
+            Entry::Vacant(entry) => {
+                let (tx, rx) = mpsc::channel(SERVICE_BACK_PRESSURE);
+                entry.insert(PrivateService {
+                    owner: owner.clone(),
+                    visitors: tx,
+                });
+
+                Ok(Registration {
+                    services: self,
+                    name,
+                    visitors: rx,
+                })
+            }
+        }
+    }
+
+    // Hands a visitor over to a private service,
+    // the visiting client needs to be allowed by the service's owner
+    //
+    // returns the owner's identifier
+    #[allow(clippy::result_large_err)]
+    pub fn visit(&self, name: &str, client: &Client, visitor: Visitor) -> Result<String, Status> {
+        let name = name.trim().to_ascii_lowercase();
+        let service = self.services.get(&name).ok_or_else(|| {
+            Status::not_found(format!("there is no private service named: {}", name))
+        })?;
+        if !service.owner.allows_visitor(client) {
+            // don't let unauthorized clients find out which services exist
+            return Err(Status::not_found(format!(
+                "there is no private service named: {}",
+                name
+            )));
+        }
+
+        match service.visitors.try_send(visitor) {
+            Ok(()) => Ok(service.owner.identifier().to_string()),
+            Err(TrySendError::Full(_)) => Err(Status::unavailable(format!(
+                "the private service: {} is overloaded",
+                name
+            ))),
+            Err(TrySendError::Closed(_)) => Err(Status::not_found(format!(
+                "there is no private service named: {}",
+                name
+            ))),
+        }
+    }
+}
+
+impl Registration {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Waits for the next visitor of this service
+    pub async fn next(&mut self) -> Option<Visitor> {
+        self.visitors.recv().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.services.services.remove(&self.name);
+    }
+}
@@ -0,0 +1,171 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use tonic::Status;
+
+use crate::auth::Client;
+
+// Tracks how many bindings and connections every client holds,
+// so a single client can't take up all of the server's resources
+#[derive(Default)]
+pub struct Quotas {
+    // Maps a client's identifier -> the amount of bindings it holds
+    bindings: DashMap<String, usize>,
+    // Maps a client's identifier -> the amount of connections it holds
+    connections: DashMap<String, usize>,
+}
+
+impl Quotas {
+    // Counts a new binding of the client, fails if it already holds as many as it may
+    //
+    // the binding is counted until the returned value is dropped
+    #[allow(clippy::result_large_err)]
+    pub fn bind(&'static self, client: &Client) -> Result<Usage, Status> {
+        Usage::acquire(&self.bindings, client, client.max_bindings()).ok_or_else(|| {
+            Status::resource_exhausted(format!(
+                "the client already holds its maximum of {} bindings",
+                client.max_bindings().unwrap_or_default()
+            ))
+        })
+    }
+
+    // Counts a new connection of the client, fails if it already holds as many as it may
+    //
+    // the connection is counted until the returned value is dropped
+    #[allow(clippy::result_large_err)]
+    pub fn connect(&'static self, client: &Client) -> Result<Usage, Status> {
+        Usage::acquire(&self.connections, client, client.max_connections()).ok_or_else(|| {
+            Status::resource_exhausted(format!(
+                "the client already holds its maximum of {} connections",
+                client.max_connections().unwrap_or_default()
+            ))
+        })
+    }
+}
+
+// A binding or connection that is counted towards a client's quota
+pub struct Usage {
+    counts: &'static DashMap<String, usize>,
+    identifier: String,
+}
+
+impl Usage {
+    // None if the client already reached the limit
+    fn acquire(
+        counts: &'static DashMap<String, usize>,
+        client: &Client,
+        limit: Option<usize>,
+    ) -> Option<Self> {
+        let allows = |count: usize| limit.is_none_or(|limit| count < limit);
+
+        // a client that is refused doesn't leave an empty entry behind
+        match counts.entry(client.identifier().to_string()) {
+            Entry::Occupied(mut count) if allows(*count.get()) => *count.get_mut() += 1,
+            Entry::Vacant(count) if allows(0) => {
+                count.insert(1);
+            }
+            _ => return None,
+        }
+
+        Some(Self {
+            counts,
+            identifier: client.identifier().to_string(),
+        })
+    }
+}
+
+impl Drop for Usage {
+    fn drop(&mut self) {
+        // forget clients that don't hold anything anymore
+        self.counts.remove_if_mut(&self.identifier, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rrp::auth::hash_token;
+    use tonic::Code;
+
+    use super::*;
+    use crate::auth::Auth;
+
+    // Alice's token is "aa" and bob's is "bb", only alice is limited
+    fn clients() -> (Client, Client) {
+        let auth = Auth::from_toml(&format!(
+            "[alice]\nhashed_token = \"{}\"\nmax_bindings = 2\nmax_connections = 1\n\
+             [bob]\nhashed_token = \"{}\"\n",
+            hash_token("aa").unwrap(),
+            hash_token("bb").unwrap(),
+        ))
+        .unwrap();
+        (auth.by_token("aa").unwrap(), auth.by_token("bb").unwrap())
+    }
+
+    fn quotas() -> &'static Quotas {
+        Box::leak(Box::default())
+    }
+
+    #[test]
+    fn limits_bindings() {
+        let (alice, _) = clients();
+        let quotas = quotas();
+
+        let first = quotas.bind(&alice).unwrap();
+        let _second = quotas.bind(&alice).unwrap();
+        assert_eq!(
+            quotas.bind(&alice).err().map(|status| status.code()),
+            Some(Code::ResourceExhausted)
+        );
+
+        // the binding's slot is freed once it's dropped
+        drop(first);
+        let _third = quotas.bind(&alice).unwrap();
+        assert!(quotas.bind(&alice).is_err());
+    }
+
+    #[test]
+    fn limits_connections_apart_from_bindings() {
+        let (alice, _) = clients();
+        let quotas = quotas();
+
+        let _binding = quotas.bind(&alice).unwrap();
+        let connection = quotas.connect(&alice).unwrap();
+        assert!(quotas.connect(&alice).is_err());
+        let _binding = quotas.bind(&alice).unwrap();
+
+        drop(connection);
+        let _connection = quotas.connect(&alice).unwrap();
+    }
+
+    #[test]
+    fn limits_every_client_on_its_own() {
+        let (alice, bob) = clients();
+        let quotas = quotas();
+
+        let _connection = quotas.connect(&alice).unwrap();
+        assert!(quotas.connect(&alice).is_err());
+        // bob isn't limited at all
+        let _connections: Vec<_> = (0..100).map(|_| quotas.connect(&bob).unwrap()).collect();
+    }
+
+    #[test]
+    fn forgets_clients_that_dont_hold_anything() {
+        let (alice, _) = clients();
+        let quotas = quotas();
+
+        let first = quotas.bind(&alice).unwrap();
+        let second = quotas.bind(&alice).unwrap();
+        assert_eq!(quotas.bindings.get("alice").as_deref(), Some(&2));
+
+        drop(first);
+        assert_eq!(quotas.bindings.get("alice").as_deref(), Some(&1));
+        drop(second);
+        assert!(quotas.bindings.is_empty());
+
+        // a refused client doesn't leave an entry behind either
+        let _connection = quotas.connect(&alice).unwrap();
+        assert!(quotas.connect(&alice).is_err());
+        assert_eq!(quotas.connections.get("alice").as_deref(), Some(&1));
+    }
+}
@@ -0,0 +1,166 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use rrp::grpc::{control, Control, Frame, Packet};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+    select,
+};
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+use crate::bandwidth::Limits;
+
+// A connection that can be relayed to a client
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {
+    // Aborts the connection, the peer is sent a RST instead of a FIN once it's closed
+    fn reset(&self);
+}
+
+impl Connection for TcpStream {
+    fn reset(&self) {
+        let _ = self.set_linger(Some(Duration::ZERO));
+    }
+}
+
+impl Connection for Box<dyn Connection> {
+    fn reset(&self) {
+        (**self).reset()
+    }
+}
+
+// The server's end of a private tunnel, the other end is relayed to the visiting client
+impl Connection for DuplexStream {
+    // an in-memory stream can't be reset, the other end just sees it closing
+    fn reset(&self) {}
+}
+
+// Connects a connection with the client's end of it
+//
+// the frames are the messages the client sends after the metadata,
+// a message that doesn't carry a frame is None.
+// the data in the prefix was already read from the connection, and is sent to the client first.
+// the traffic of both directions is shaped by the limits
+pub fn relay<C, S>(
+    mut conn: C,
+    prefix: Vec<u8>,
+    mut frames: S,
+    limits: Limits,
+) -> impl Stream<Item = Result<Frame, Status>>
+where
+    C: Connection,
+    S: Stream<Item = Result<Option<Frame>, Status>> + Unpin,
+{
+    async_stream::stream! {
+        // the data of each direction that waits for the limits to let it through,
+        // a direction stops reading while its data waits, without holding up the other one
+        let mut download = None;
+        let mut upload = None;
+
+        // forward whatever was read before the connection was relayed
+        if !prefix.is_empty() {
+            download = Some(shape(prefix, |amount| limits.download(amount)));
+        }
+
+        let mut data = vec![0u8; 4096];
+        // whether each side has finished sending, the connection is done once both have
+        let mut conn_fin = false;
+        let mut client_fin = false;
+        while !(conn_fin && client_fin) {
+            select! {
+                shaped = wait(&mut download) => {
+                    download = None;
+                    yield Ok(Packet { data: shaped }.into());
+                }
+
+                shaped = wait(&mut upload) => {
+                    upload = None;
+                    if let Err(err) = conn.write_all(&shaped).await {
+                        yield Ok(Control::from_io_error(&err).into());
+                        break;
+                    }
+                }
+
+                rcount = conn.read(&mut data), if !conn_fin && download.is_none() => {
+                    match rcount {
+                        Ok(0) => {
+                            // the connection's peer has closed its writing part
+                            conn_fin = true;
+                            yield Ok(Control::fin().into());
+                        }
+                        Ok(rcount) => {
+                            download = Some(shape(data[..rcount].to_vec(), |amount| limits.download(amount)));
+                        }
+                        Err(err) => {
+                            yield Ok(Control::from_io_error(&err).into());
+                            break;
+                        }
+                    }
+                }
+
+                frame = frames.next(), if !client_fin && upload.is_none() => {
+                    let frame = match frame {
+                        Some(Ok(Some(frame))) => frame,
+                        // the client went away without finishing the connection
+                        None => {
+                            conn.reset();
+                            break;
+                        }
+                        Some(Ok(None)) => {
+                            conn.reset();
+                            yield Err(Status::invalid_argument("all messages, except the first one, need to contain either a packet or a control frame"));
+                            break;
+                        }
+                        Some(Err(err)) => {
+                            conn.reset();
+                            yield Err(err);
+                            break;
+                        }
+                    };
+
+                    match frame {
+                        Frame::Packet(packet) => {
+                            upload = Some(shape(packet.data, |amount| limits.upload(amount)));
+                        }
+                        Frame::Control(control) => match control.kind() {
+                            control::Kind::Fin => {
+                                // the client's end has closed its writing part
+                                client_fin = true;
+                                if let Err(err) = conn.shutdown().await {
+                                    yield Ok(Control::from_io_error(&err).into());
+                                    break;
+                                }
+                            }
+                            control::Kind::Rst | control::Kind::Error => {
+                                conn.reset();
+                                break;
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Data that is let through once the limits allow it
+type Shaped<'a> = Pin<Box<dyn Future<Output = Vec<u8>> + Send + 'a>>;
+
+fn shape<'a, F>(data: Vec<u8>, limit: impl FnOnce(usize) -> F) -> Shaped<'a>
+where
+    F: Future<Output = ()> + Send + 'a,
+{
+    let allowed = limit(data.len());
+    Box::pin(async move {
+        allowed.await;
+        data
+    })
+}
+
+// Waits for the shaped data of a direction, forever if it has nothing waiting
+async fn wait(shaped: &mut Option<Shaped<'_>>) -> Vec<u8> {
+    match shaped {
+        Some(shaped) => shaped.await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,265 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use dashmap::{mapref::entry::Entry, DashMap};
+use rrp::grpc::{HostProtocol, LoadBalancing};
+use tokio::{
+    io::AsyncWrite,
+    net::{TcpListener, TcpStream},
+    select,
+    time::{sleep, timeout},
+};
+use tonic::Status;
+
+use crate::{
+    auth::Client,
+    config::Config,
+    groups::{Group, Membership},
+    http::{self, EdgeAuth},
+    shutdown::Shutdown,
+    sni, utils,
+    visitor::{Visitor, VisitorFilter},
+};
+
+// How long a visitor has to send enough data for us to route it,
+// including a PROXY protocol header
+const ROUTING_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Listens on a port that is shared between many bindings,
+// and routes every incoming connection to a binding by its hostname.
+//
+// http connections are routed by the host header of their first request,
+// and tls connections by the server name of their ClientHello.
+// either way, the connection is forwarded untouched.
+pub struct HostRouter {
+    protocol: HostProtocol,
+    port: u16,
+    config: &'static Config,
+
+    // Single label hostnames are registered as subdomains of the base domain
+    base_domain: Option<String>,
+
+    // Maps a hostname -> the bindings it's routed to
+    routes: DashMap<String, RouteTarget>,
+}
+
+// Where the visitors of a hostname are sent, and what they need to present to get there
+#[derive(Clone)]
+struct RouteTarget {
+    group: Arc<Group>,
+    auth: Arc<EdgeAuth>,
+}
+
+// A binding of a hostname,
+// the hostname is released once the routes of all of its bindings are dropped
+pub struct Route {
+    router: &'static HostRouter,
+    hostname: String,
+    membership: Membership,
+}
+
+impl HostRouter {
+    // Binds the shared port and starts routing connections in the background
+    pub async fn bind(
+        protocol: HostProtocol,
+        port: u16,
+        config: &'static Config,
+        shutdown: &'static Shutdown,
+    ) -> anyhow::Result<&'static Self> {
+        let listener = utils::bind_tcp((config.default_bind_address(), port).into())
+            .with_context(|| format!("failed to listen on the shared port: {}", port))?;
+
+        // The router is used throughout the entire lifetime of the app
+        let router: &'static _ = Box::leak(Box::new(Self {
+            protocol,
+            port: listener.local_addr()?.port(),
+            config,
+            base_domain: config
+                .base_domain
+                .as_ref()
+                .map(|domain| domain.trim_matches('.').to_ascii_lowercase()),
+            routes: DashMap::new(),
+        }));
+        tokio::spawn(router.serve(listener, shutdown));
+
+        Ok(router)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Registers a hostname, visitors for it can be received from the returned route
+    //
+    // http visitors are only routed once they present the credentials the auth requires,
+    // a load balanced hostname can be registered again by the client's other bindings
+    #[allow(clippy::result_large_err)]
+    pub fn register(
+        &'static self,
+        hostname: &str,
+        client: &Client,
+        strategy: Option<LoadBalancing>,
+        auth: EdgeAuth,
+    ) -> Result<Route, Status> {
+        let hostname = self.qualify(hostname)?;
+        if auth.is_required() && self.protocol != HostProtocol::Http {
+            return Err(Status::invalid_argument(
+                "only http bindings can require credentials from their visitors",
+            ));
+        }
+
+        let membership = match self.routes.entry(hostname.clone()) {
+            Entry::Occupied(entry) => {
+                let target = entry.get();
+                let name = format!("the hostname: {}", hostname);
+                target.group.admits(client, strategy, name)?;
+                if *target.auth != auth {
+                    return Err(Status::failed_precondition(format!(
+                        "the hostname: {} is already bound with other credentials",
+                        hostname
+                    )));
+                }
+
+                target.group.join(VisitorFilter::default())
+            }
+            Entry::Vacant(entry) => {
+                let group = Group::new(client, strategy);
+                let membership = group.join(VisitorFilter::default());
+                entry.insert(RouteTarget {
+                    group,
+                    auth: Arc::new(auth),
+                });
+
+                membership
+            }
+        };
+
+        Ok(Route {
+            router: self,
+            hostname,
+            membership,
+        })
+    }
+
+    // Turns a requested hostname into the fully qualified hostname that visitors will use
+    #[allow(clippy::result_large_err)]
+    fn qualify(&self, hostname: &str) -> Result<String, Status> {
+        let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+
+        let valid = !hostname.is_empty()
+            && hostname.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            return Err(Status::invalid_argument(format!(
+                "invalid hostname: {}",
+                hostname
+            )));
+        }
+
+        Ok(match &self.base_domain {
+            Some(base_domain) if !hostname.contains('.') => {
+                format!("{}.{}", hostname, base_domain)
+            }
+            _ => hostname,
+        })
+    }
+
+    // Routes incoming connections until the server shuts down
+    async fn serve(&'static self, listener: TcpListener, shutdown: &'static Shutdown) {
+        loop {
+            let accepted = select! {
+                accepted = listener.accept() => accepted,
+                // dropping the listener closes the shared port
+                _ = shutdown.wait() => break,
+            };
+            let (stream, peer_addr) = match accepted {
+                Ok(conn) => conn,
+                Err(err) => {
+                    eprintln!("failed to accept a connection on a shared port: {}", err);
+                    sleep(utils::ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            // routing requires reading from the visitor, don't let it block the other visitors
+            tokio::spawn(async move {
+                // the visitor went away or sent garbage, there is nothing we can do about it
+                let _ = self.route(stream, peer_addr).await;
+            });
+        }
+    }
+
+    async fn route(&self, stream: TcpStream, peer_addr: SocketAddr) -> std::io::Result<()> {
+        let mut visitor = timeout(ROUTING_TIMEOUT, async {
+            let mut visitor = Visitor::accept(stream, peer_addr, self.config).await?;
+            match self.protocol {
+                HostProtocol::Http => http::read_request_head(&mut visitor).await?,
+                HostProtocol::Tls => sni::read_client_hello(&mut visitor).await?,
+            }
+
+            Ok::<_, std::io::Error>(visitor)
+        })
+        .await??;
+
+        let hostname = match self.protocol {
+            HostProtocol::Http => http::parse_host(&visitor.prefix),
+            HostProtocol::Tls => sni::parse_sni(&visitor.prefix),
+        };
+
+        let route =
+            hostname.and_then(|hostname| self.routes.get(&hostname).map(|route| route.clone()));
+        let Some(route) = route else {
+            return self.reject(&mut visitor.stream, http::NOT_FOUND).await;
+        };
+        if !route.auth.authorizes(&visitor.prefix) {
+            return http::respond_unauthorized(&mut visitor.stream, &route.auth).await;
+        }
+        if route.auth.is_required() {
+            http::close_after_response(&mut visitor.prefix);
+        }
+
+        match route.group.dispatch(visitor) {
+            None => Ok(()),
+            Some(mut visitor) => {
+                self.reject(&mut visitor.stream, http::SERVICE_UNAVAILABLE)
+                    .await
+            }
+        }
+    }
+
+    // Lets the visitor know that it can't be routed
+    //
+    // we don't hold the keys of tls bindings, so tls visitors are simply disconnected
+    async fn reject(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        status: &str,
+    ) -> std::io::Result<()> {
+        match self.protocol {
+            HostProtocol::Http => http::respond(stream, status).await,
+            HostProtocol::Tls => Ok(()),
+        }
+    }
+}
+
+impl Route {
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    // Waits for the next visitor that was routed to this binding
+    pub async fn next(&mut self) -> Option<Visitor> {
+        self.membership.next().await
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        // the hostname stays bound as long as any of the group's bindings is left
+        self.membership.leave();
+        self.router
+            .routes
+            .remove_if(&self.hostname, |_, target| target.group.is_empty());
+    }
+}
@@ -1,80 +1,252 @@
-use std::pin::Pin;
-
-use dashmap::DashMap;
-use rrp::grpc::{
-    reverse_proxy_server::{ReverseProxy, ReverseProxyServer},
-    tcp_accept_request, tcp_bind_response, Packet, TcpAcceptRequest, TcpBindRequest,
-    TcpBindResponse, TcpBindResponseMetadata, TcpNewConnection,
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    time::Duration,
 };
-use tokio::{
-    io::AsyncReadExt,
-    net::{TcpListener, TcpStream},
-    select,
+
+use rrp::{
+    grpc::{
+        host_bind_request, host_bind_response, private_bind_request, private_bind_response,
+        private_visit_request, private_visit_response,
+        reverse_proxy_server::{ReverseProxy, ReverseProxyServer},
+        tcp_accept_request, tcp_accept_response, tcp_bind_request, tcp_bind_response,
+        tcp_connect_request, tcp_connect_response, udp_bind_request, udp_bind_response, Datagram,
+        HelloRequest, HelloResponse, HostBindRequest, HostBindResponse, HostBindResponseMetadata,
+        HostProtocol, PrivateBindRequest, PrivateBindResponse, PrivateBindResponseMetadata,
+        PrivateVisitRequest, PrivateVisitResponse, PrivateVisitResponseMetadata, TcpAcceptRequest,
+        TcpAcceptResponse, TcpAcceptResponseMetadata, TcpBindRequest, TcpBindResponse,
+        TcpBindResponseMetadata, TcpConnectRequest, TcpConnectResponse, TcpConnectResponseMetadata,
+        UdpBindRequest, UdpBindResponse, UdpBindResponseMetadata, UdpSessionClosed,
+    },
+    handshake::{self, capabilities},
 };
+use tokio::{net::TcpStream, select};
 use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 
-use crate::utils::{self, parse_port};
+use crate::{
+    announce::announce,
+    auth::{authenticated_client, Auth},
+    bandwidth::ClientLimits,
+    config::Config,
+    connections::PendingConnections,
+    groups::PortGroups,
+    heartbeat::Heartbeats,
+    http::EdgeAuth,
+    ports::PortPolicy,
+    private::PrivateServices,
+    quotas::Quotas,
+    relay::relay,
+    router::HostRouter,
+    shutdown::Shutdown,
+    udp::{Sessions, MAX_DATAGRAM_SIZE},
+    utils::{self, parse_port},
+    visitor::{Visitor, VisitorFilter},
+};
 
-// Maps a port -> TcpStream
-type ConnectionQueue = DashMap<u16, Vec<TcpStream>>;
+// How often idle udp sessions are looked for
+const UDP_SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// How long connecting to a destination may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How much data may be buffered between both ends of a private tunnel
+const PRIVATE_TUNNEL_BUFFER_SIZE: usize = 64 * 1024;
 
 pub struct ReverseProxyService {
-    pending_connections: &'static ConnectionQueue,
+    config: &'static Config,
+    ports: PortPolicy,
+    http_router: Option<&'static HostRouter>,
+    tls_router: Option<&'static HostRouter>,
+    pending_connections: &'static PendingConnections,
+    private_services: &'static PrivateServices,
+    limits: &'static ClientLimits,
+    quotas: &'static Quotas,
+    port_groups: &'static PortGroups,
+    shutdown: &'static Shutdown,
 }
 
 impl ReverseProxyService {
-    pub fn new() -> ReverseProxyServer<Self> {
+    pub fn new(
+        config: &'static Config,
+        auth: &'static Auth,
+        http_router: Option<&'static HostRouter>,
+        tls_router: Option<&'static HostRouter>,
+        shutdown: &'static Shutdown,
+    ) -> ReverseProxyServer<Self> {
         // The service is used throughout the entire lifetime of the app
-        let pending_connections = Box::leak(Box::default());
+        let pending_connections =
+            Box::leak(Box::new(PendingConnections::new(config.accept_timeout)));
+        let private_services = Box::leak(Box::default());
+        let limits = Box::leak(Box::default());
+        let quotas = Box::leak(Box::default());
+        let port_groups = Box::leak(Box::new(PortGroups::new(config, auth)));
 
         ReverseProxyServer::new(Self {
+            config,
+            ports: PortPolicy::new(config, auth),
+            http_router,
+            tls_router,
             pending_connections,
+            private_services,
+            limits,
+            quotas,
+            port_groups,
+            shutdown,
         })
     }
 }
 
+impl ReverseProxyService {
+    // The address a client's port is exposed on, the server's default one if the client didn't ask for any
+    #[allow(clippy::result_large_err)]
+    fn bind_address(&self, requested: Option<&str>) -> Result<IpAddr, Status> {
+        let Some(requested) = requested else {
+            return Ok(self.config.default_bind_address());
+        };
+
+        let ip = utils::parse_ip(requested)?;
+        if !self.config.allows_bind_address(ip) {
+            return Err(Status::permission_denied(format!(
+                "exposing ports on {} is not allowed",
+                ip
+            )));
+        }
+
+        Ok(ip)
+    }
+}
+
+#[allow(clippy::result_large_err)]
 #[tonic::async_trait]
 impl ReverseProxy for ReverseProxyService {
+    async fn hello(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<HelloResponse>, Status> {
+        let request = request.into_inner();
+        if !handshake::is_compatible(request.protocol_version) {
+            return Err(Status::failed_precondition(format!(
+                "the client speaks protocol version {} (build {}), but the server speaks versions {} to {} (build {}), please upgrade the {}",
+                request.protocol_version,
+                request.build_version,
+                handshake::MIN_PROTOCOL_VERSION,
+                handshake::PROTOCOL_VERSION,
+                handshake::BUILD_VERSION,
+                match request.protocol_version < handshake::MIN_PROTOCOL_VERSION {
+                    true => "client",
+                    false => "server",
+                }
+            )));
+        }
+
+        let mut capabilities = vec![
+            capabilities::TCP,
+            capabilities::UDP,
+            capabilities::PROXY_PROTOCOL,
+            capabilities::BIND_ADDRESS,
+            capabilities::VISITOR_FILTERS,
+            capabilities::LOAD_BALANCING,
+            capabilities::CONNECT,
+            capabilities::PRIVATE,
+            capabilities::MULTIPLEXING,
+        ];
+        if self.http_router.is_some() {
+            capabilities.push(capabilities::HTTP);
+            capabilities.push(capabilities::EDGE_AUTH);
+        }
+        if self.tls_router.is_some() {
+            capabilities.push(capabilities::TLS);
+        }
+
+        Ok(Response::new(HelloResponse {
+            protocol_version: handshake::PROTOCOL_VERSION,
+            build_version: handshake::BUILD_VERSION.to_string(),
+            capabilities: capabilities.into_iter().map(String::from).collect(),
+        }))
+    }
+
     type BindTcpStream =
         Pin<Box<dyn Stream<Item = Result<TcpBindResponse, Status>> + Send + 'static>>;
 
     async fn bind_tcp(
         &self,
-        request: Request<TcpBindRequest>,
+        request: Request<Streaming<TcpBindRequest>>,
     ) -> Result<Response<Self::BindTcpStream>, Status> {
-        let request = request.into_inner();
-        // From tokio's docs
-        // "Binding with a port number of 0 will request that the OS assigns a port to this listener."
-        // " The port allocated can be queried via the local_addr method."
-        let port = request.port.unwrap_or(0);
-        let port = utils::parse_port(port)?;
-
-        let listener = TcpListener::bind(("0.0.0.0", port)).await.map_err(|err| {
-            Status::internal(format!("failed to start a new tcp server:\n{:?}", err))
-        })?;
-        let port = listener.local_addr()?.port();
+        let client = authenticated_client(&request)?;
+        let mut stream = request.into_inner();
+
+        // Extract the metadata
+        let metadata = stream
+            .next()
+            .await
+            .map(|msg| {
+                msg.and_then(|data| match data.request {
+                    Some(tcp_bind_request::Request::Metadata(metadata)) => Ok(metadata),
+                    _ => Err(Status::invalid_argument(
+                        "the first message needs to contain metadata",
+                    )),
+                })
+            })
+            .ok_or_else(|| Status::cancelled("empty request"))??;
+
+        let binding = self.quotas.bind(&client)?;
 
-        // // Create an async stream that accepts connections and inserts them inside a waiting queue
-        // let output = AcceptConnectionsStream::new(listener, port, self.pending_connections);
+        // a free port is picked for the client if it didn't ask for one
+        let port = metadata.port.map(utils::parse_port).transpose()?;
+        let ip = self.bind_address(metadata.bind_address.as_deref())?;
+        let filter = VisitorFilter::parse(&metadata.allow_visitors, &metadata.deny_visitors)?;
+        let strategy = metadata
+            .load_balancing
+            .map(utils::parse_load_balancing)
+            .transpose()?;
+
+        let (shared, mut membership) =
+            self.port_groups
+                .bind(&client, ip, port, strategy, filter, || {
+                    self.ports
+                        .bind(&client, port, |port| utils::bind_tcp((ip, port).into()))?
+                        .map_err(|err| {
+                            Status::internal(format!(
+                                "failed to start a new tcp server:\n{:?}",
+                                err
+                            ))
+                        })
+                })?;
+        let port = shared.port();
+
+        let config = self.config;
+        let shutdown = self.shutdown;
+        let queue = self
+            .pending_connections
+            .open(&client, self.limits.binding(&client));
+        let requests = stream.map(|msg| msg.map(TcpBindRequest::into_heartbeat));
+        // the port's visitors are accepted by its group, and routed to whichever binding should handle them
+        let visitors = async_stream::stream! {
+            while let Some(visitor) = membership.next().await {
+                yield visitor;
+            }
+        };
+        let announcements = announce(config, shutdown, queue, visitors, requests, |connection| {
+            TcpBindResponse {
+                response: Some(tcp_bind_response::Response::Connection(connection)),
+            }
+        });
+        let output = async_stream::stream! {
+            let _binding = binding;
+            let _shared = shared;
 
-        let queue = self.pending_connections;
-        let output = async_stream::try_stream! {
             // The first message needs to contain metadata
-            yield TcpBindResponse {
+            yield Ok(TcpBindResponse {
                 response: Some(tcp_bind_response::Response::Metadata(
-                    TcpBindResponseMetadata { port: port as i32 },
+                    TcpBindResponseMetadata {
+                        port: port as i32,
+                        bind_address: ip.to_string(),
+                    },
                 )),
-            };
+            });
 
-            loop {
-                let (conn, _) = listener.accept().await?;
-
-                // save the connection in the queue and let the client know that there is a new pending connection
-                queue.entry(port).or_default().push(conn);
-                yield TcpBindResponse {
-                    response: Some(tcp_bind_response::Response::Connection(TcpNewConnection {})),
-                }
+            for await announcement in announcements {
+                yield announcement;
             }
         };
 
@@ -82,12 +254,13 @@ impl ReverseProxy for ReverseProxyService {
     }
 
     type AcceptTcpConnectionStream =
-        Pin<Box<dyn Stream<Item = Result<Packet, Status>> + Send + 'static>>;
+        Pin<Box<dyn Stream<Item = Result<TcpAcceptResponse, Status>> + Send + 'static>>;
 
     async fn accept_tcp_connection(
         &self,
         request: Request<Streaming<TcpAcceptRequest>>,
     ) -> Result<Response<Self::AcceptTcpConnectionStream>, Status> {
+        let client = authenticated_client(&request)?;
         let mut stream = request.into_inner();
 
         // Extract the metadata
@@ -103,41 +276,135 @@ impl ReverseProxy for ReverseProxyService {
                 })
             })
             .ok_or_else(|| Status::cancelled("empty request"))??;
-        let port = parse_port(metadata.port)?;
 
-        // Poll a connection from the queue
-        let mut conn = self
+        // Claim the connection from the queue
+        let (visitor, limits) = self
             .pending_connections
-            .get_mut(&port)
-            .and_then(|mut queue| queue.pop())
-            .ok_or_else(|| {
-                Status::invalid_argument(format!(
-                    "there are no pending connections on port: {}",
-                    port
-                ))
-            })?;
+            .claim(&client, metadata.connection_id)?;
+        // a visitor that is over the quota is closed right away instead of waiting to expire
+        let connection = self.quotas.connect(&client)?;
+        let info = visitor.info();
+        let Visitor {
+            stream: conn,
+            prefix,
+            load,
+            ..
+        } = visitor;
+        let frames = stream.map(|msg| msg.map(TcpAcceptRequest::into_frame));
 
         // Create a stream that connects both ends of the connections together
         let output = async_stream::stream! {
-            let mut data = vec![0u8; 4096];
-            let mut client_eof = false;
+            // the connection counts towards the client's quota,
+            // and towards its binding's load, for as long as it's open
+            let _connection = connection;
+            let _load = load;
+
+            // The first message needs to contain metadata
+            yield Ok(TcpAcceptResponse {
+                response: Some(tcp_accept_response::Response::Metadata(
+                    TcpAcceptResponseMetadata { info: Some(info) },
+                )),
+            });
+
+            for await frame in relay(conn, prefix, frames, limits) {
+                yield frame.map(TcpAcceptResponse::from);
+            }
+        };
+
+        Ok(Response::new(
+            Box::pin(output) as Self::AcceptTcpConnectionStream
+        ))
+    }
+
+    type BindUdpStream =
+        Pin<Box<dyn Stream<Item = Result<UdpBindResponse, Status>> + Send + 'static>>;
+
+    async fn bind_udp(
+        &self,
+        request: Request<Streaming<UdpBindRequest>>,
+    ) -> Result<Response<Self::BindUdpStream>, Status> {
+        let client = authenticated_client(&request)?;
+        let mut stream = request.into_inner();
+
+        // Extract the metadata
+        let metadata = stream
+            .next()
+            .await
+            .map(|msg| {
+                msg.and_then(|data| match data.request {
+                    Some(udp_bind_request::Request::Metadata(metadata)) => Ok(metadata),
+                    _ => Err(Status::invalid_argument(
+                        "the first message needs to contain metadata",
+                    )),
+                })
+            })
+            .ok_or_else(|| Status::cancelled("empty request"))??;
+        let binding = self.quotas.bind(&client)?;
+
+        // a free port is picked for the client if it didn't ask for one
+        let port = metadata.port.map(parse_port).transpose()?;
+        let ip = self.bind_address(metadata.bind_address.as_deref())?;
+
+        let socket = self
+            .ports
+            .bind(&client, port, |port| utils::bind_udp((ip, port).into()))?
+            .map_err(|err| {
+                Status::internal(format!("failed to bind a new udp socket:\n{:?}", err))
+            })?;
+        let port = socket.local_addr()?.port();
+
+        let config = self.config;
+        let shutdown = self.shutdown;
+        let limits = self.limits.binding(&client);
+        let session_timeout = config.udp_session_timeout;
+        let output = async_stream::stream! {
+            let _binding = binding;
+
+            // The first message needs to contain metadata
+            yield Ok(UdpBindResponse {
+                response: Some(udp_bind_response::Response::Metadata(
+                    UdpBindResponseMetadata {
+                        port: port as i32,
+                        bind_address: ip.to_string(),
+                    },
+                )),
+            });
+
+            let mut sessions = Sessions::new(config.max_udp_sessions);
+            let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+            let mut sweep = tokio::time::interval(UDP_SESSION_SWEEP_INTERVAL);
+            let mut heartbeats = Heartbeats::new(config);
             loop {
                 select! {
-                    rcount = conn.read(&mut data), if !client_eof => {
-                        match rcount {
-                            Err(_) => continue,
-                            Ok(rcount) => {
-                                yield Ok(Packet {
-                                    data: data[..rcount].to_vec()
-                                });
-
-                                if rcount == 0 {
-                                    // the client has closed its writing part,
-                                    // the proxy-client is notified by receiving an empty Packet
-                                    client_eof = true;
-                                }
+                    received = socket.recv_from(&mut data) => {
+                        let (rcount, addr) = match received {
+                            Ok(received) => received,
+                            // an icmp error caused by a previous reply, it has nothing to do with the socket itself
+                            Err(err) if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused) => continue,
+                            Err(err) => {
+                                yield Err(err.into());
+                                break;
                             }
+                        };
+
+                        // datagrams that are over the limits are dropped, like on any congested link,
+                        // and so are the ones of new visitors while the binding has all the sessions it may
+                        if config.denies_visitor(addr.ip()) {
+                            continue;
                         }
+                        let Some(session_id) = sessions.touch(addr) else {
+                            continue;
+                        };
+                        if !limits.try_download(rcount) {
+                            continue;
+                        }
+
+                        yield Ok(UdpBindResponse {
+                            response: Some(udp_bind_response::Response::Datagram(Datagram {
+                                session_id,
+                                data: data[..rcount].to_vec(),
+                            })),
+                        });
                     }
 
                     msg = stream.next() => {
@@ -149,25 +416,333 @@ impl ReverseProxy for ReverseProxyService {
                                 break;
                             }
                         };
-                        let packet = match msg.request {
-                            Some(tcp_accept_request::Request::Packet(packet)) => packet,
+                        let datagram = match msg.request {
+                            Some(udp_bind_request::Request::Datagram(datagram)) => datagram,
+                            Some(udp_bind_request::Request::Heartbeat(heartbeat)) => {
+                                if let Err(err) = heartbeats.ack(&heartbeat) {
+                                    yield Err(err);
+                                    break;
+                                }
+                                continue;
+                            }
                             _ => {
-                                yield Err(Status::invalid_argument("all messages, except the first one, need to contain a packet"));
+                                yield Err(Status::invalid_argument("all messages, except the first one, need to contain either a datagram or a heartbeat"));
                                 break;
                             }
                         };
 
-                        if let Err(err) = tokio::io::copy(&mut &packet.data[..], &mut conn).await {
-                            yield Err(err.into());
+                        // replies to expired sessions, ones that are over the limits
+                        // or ones that fail to be sent, are discarded, just like a lost datagram
+                        if let Some(addr) = sessions.reply_to(datagram.session_id) {
+                            if limits.try_upload(datagram.data.len()) {
+                                let _ = socket.send_to(&datagram.data, addr).await;
+                            }
+                        }
+                    }
+
+                    status = shutdown.unbind() => {
+                        // the sessions end along with the stream, there is nothing else to drain
+                        yield Err(status);
+                        break;
+                    }
+
+                    heartbeat = heartbeats.tick() => match heartbeat {
+                        Ok(heartbeat) => yield Ok(heartbeat.into()),
+                        // the client is gone, dropping the socket frees the port
+                        Err(err) => {
+                            yield Err(err);
                             break;
                         }
+                    },
+
+                    _ = sweep.tick() => {
+                        for session_id in sessions.expire(session_timeout) {
+                            yield Ok(UdpBindResponse {
+                                response: Some(udp_bind_response::Response::Closed(UdpSessionClosed {
+                                    session_id,
+                                })),
+                            });
+                        }
                     }
                 }
             }
         };
 
-        Ok(Response::new(
-            Box::pin(output) as Self::AcceptTcpConnectionStream
-        ))
+        Ok(Response::new(Box::pin(output) as Self::BindUdpStream))
+    }
+
+    type BindHostStream =
+        Pin<Box<dyn Stream<Item = Result<HostBindResponse, Status>> + Send + 'static>>;
+
+    async fn bind_host(
+        &self,
+        request: Request<Streaming<HostBindRequest>>,
+    ) -> Result<Response<Self::BindHostStream>, Status> {
+        let client = authenticated_client(&request)?;
+        let mut stream = request.into_inner();
+
+        // Extract the metadata
+        let request = stream
+            .next()
+            .await
+            .map(|msg| {
+                msg.and_then(|data| match data.request {
+                    Some(host_bind_request::Request::Metadata(metadata)) => Ok(metadata),
+                    _ => Err(Status::invalid_argument(
+                        "the first message needs to contain metadata",
+                    )),
+                })
+            })
+            .ok_or_else(|| Status::cancelled("empty request"))??;
+
+        let binding = self.quotas.bind(&client)?;
+
+        let router = match request.protocol() {
+            HostProtocol::Http => self.http_router,
+            HostProtocol::Tls => self.tls_router,
+        }
+        .ok_or_else(|| {
+            Status::unimplemented(format!(
+                "the server does not route {} bindings",
+                request.protocol().as_str_name().to_lowercase()
+            ))
+        })?;
+
+        let strategy = request
+            .load_balancing
+            .map(utils::parse_load_balancing)
+            .transpose()?;
+        let auth = EdgeAuth::new(request.basic_auth, request.bearer_token)?;
+        let mut route = router.register(&request.hostname, &client, strategy, auth)?;
+        let port = router.port();
+
+        let config = self.config;
+        let shutdown = self.shutdown;
+        let queue = self
+            .pending_connections
+            .open(&client, self.limits.binding(&client));
+        let hostname = route.hostname().to_string();
+        let requests = stream.map(|msg| msg.map(HostBindRequest::into_heartbeat));
+        let visitors = async_stream::stream! {
+            while let Some(visitor) = route.next().await {
+                yield visitor;
+            }
+        };
+        let announcements = announce(config, shutdown, queue, visitors, requests, |connection| {
+            HostBindResponse {
+                response: Some(host_bind_response::Response::Connection(connection)),
+            }
+        });
+        let output = async_stream::stream! {
+            let _binding = binding;
+
+            // The first message needs to contain metadata
+            yield Ok(HostBindResponse {
+                response: Some(host_bind_response::Response::Metadata(
+                    HostBindResponseMetadata {
+                        hostname,
+                        port: port as i32,
+                    },
+                )),
+            });
+
+            for await announcement in announcements {
+                yield announcement;
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::BindHostStream))
+    }
+
+    type ConnectTcpStream =
+        Pin<Box<dyn Stream<Item = Result<TcpConnectResponse, Status>> + Send + 'static>>;
+
+    async fn connect_tcp(
+        &self,
+        request: Request<Streaming<TcpConnectRequest>>,
+    ) -> Result<Response<Self::ConnectTcpStream>, Status> {
+        let client = authenticated_client(&request)?;
+        let mut stream = request.into_inner();
+
+        // Extract the metadata
+        let metadata = stream
+            .next()
+            .await
+            .map(|msg| {
+                msg.and_then(|data| match data.request {
+                    Some(tcp_connect_request::Request::Metadata(metadata)) => Ok(metadata),
+                    _ => Err(Status::invalid_argument(
+                        "the first message needs to contain metadata",
+                    )),
+                })
+            })
+            .ok_or_else(|| Status::cancelled("empty request"))??;
+        let port = parse_port(metadata.port)?;
+        let connection = self.quotas.connect(&client)?;
+
+        // only the resolved addresses that the server allows are connected to
+        let addr = tokio::net::lookup_host((metadata.host.as_str(), port))
+            .await
+            .map_err(|err| {
+                Status::not_found(format!("failed to resolve {}:\n{:?}", metadata.host, err))
+            })?
+            .find(|addr| {
+                self.config.allows_destination(&metadata.host, *addr)
+                    || client.allows_destination(&metadata.host, *addr)
+            })
+            .ok_or_else(|| {
+                Status::permission_denied(format!(
+                    "connecting to {}:{} is not allowed",
+                    metadata.host, port
+                ))
+            })?;
+
+        let conn = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Status::deadline_exceeded(format!("connecting to {} timed out", addr)))?
+            .map_err(|err| {
+                Status::unavailable(format!("failed to connect to {}:\n{:?}", addr, err))
+            })?;
+        let frames = stream.map(|msg| msg.map(TcpConnectRequest::into_frame));
+        let limits = self.limits.client(&client);
+
+        // Create a stream that connects both ends of the connections together
+        let output = async_stream::stream! {
+            let _connection = connection;
+
+            // The first message needs to contain metadata
+            yield Ok(TcpConnectResponse {
+                response: Some(tcp_connect_response::Response::Metadata(
+                    TcpConnectResponseMetadata { remote_addr: addr.to_string() },
+                )),
+            });
+
+            for await frame in relay(conn, Vec::new(), frames, limits) {
+                yield frame.map(TcpConnectResponse::from);
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::ConnectTcpStream))
+    }
+
+    type BindPrivateStream =
+        Pin<Box<dyn Stream<Item = Result<PrivateBindResponse, Status>> + Send + 'static>>;
+
+    async fn bind_private(
+        &self,
+        request: Request<Streaming<PrivateBindRequest>>,
+    ) -> Result<Response<Self::BindPrivateStream>, Status> {
+        let client = authenticated_client(&request)?;
+        let mut stream = request.into_inner();
+
+        // Extract the metadata
+        let request = stream
+            .next()
+            .await
+            .map(|msg| {
+                msg.and_then(|data| match data.request {
+                    Some(private_bind_request::Request::Metadata(metadata)) => Ok(metadata),
+                    _ => Err(Status::invalid_argument(
+                        "the first message needs to contain metadata",
+                    )),
+                })
+            })
+            .ok_or_else(|| Status::cancelled("empty request"))??;
+
+        let binding = self.quotas.bind(&client)?;
+        let mut registration = self.private_services.register(&request.name, &client)?;
+
+        let config = self.config;
+        let shutdown = self.shutdown;
+        let queue = self
+            .pending_connections
+            .open(&client, self.limits.binding(&client));
+        let name = registration.name().to_string();
+        let requests = stream.map(|msg| msg.map(PrivateBindRequest::into_heartbeat));
+        let visitors = async_stream::stream! {
+            while let Some(visitor) = registration.next().await {
+                yield visitor;
+            }
+        };
+        let announcements = announce(config, shutdown, queue, visitors, requests, |connection| {
+            PrivateBindResponse {
+                response: Some(private_bind_response::Response::Connection(connection)),
+            }
+        });
+        let output = async_stream::stream! {
+            let _binding = binding;
+
+            // The first message needs to contain metadata
+            yield Ok(PrivateBindResponse {
+                response: Some(private_bind_response::Response::Metadata(
+                    PrivateBindResponseMetadata {
+                        name,
+                    },
+                )),
+            });
+
+            for await announcement in announcements {
+                yield announcement;
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::BindPrivateStream))
+    }
+
+    type VisitPrivateStream =
+        Pin<Box<dyn Stream<Item = Result<PrivateVisitResponse, Status>> + Send + 'static>>;
+
+    async fn visit_private(
+        &self,
+        request: Request<Streaming<PrivateVisitRequest>>,
+    ) -> Result<Response<Self::VisitPrivateStream>, Status> {
+        let client = authenticated_client(&request)?;
+        let unknown_addr = || SocketAddr::from(([0, 0, 0, 0], 0));
+        let peer_addr = utils::canonical_addr(request.remote_addr().unwrap_or_else(unknown_addr));
+        let local_addr = utils::canonical_addr(request.local_addr().unwrap_or_else(unknown_addr));
+        let mut stream = request.into_inner();
+
+        // Extract the metadata
+        let metadata = stream
+            .next()
+            .await
+            .map(|msg| {
+                msg.and_then(|data| match data.request {
+                    Some(private_visit_request::Request::Metadata(metadata)) => Ok(metadata),
+                    _ => Err(Status::invalid_argument(
+                        "the first message needs to contain metadata",
+                    )),
+                })
+            })
+            .ok_or_else(|| Status::cancelled("empty request"))??;
+
+        let connection = self.quotas.connect(&client)?;
+
+        // the service's owner accepts the other end, just like any other visitor
+        let (conn, service_end) = tokio::io::duplex(PRIVATE_TUNNEL_BUFFER_SIZE);
+        let visitor = Visitor::private(service_end, peer_addr, local_addr);
+        let owner = self
+            .private_services
+            .visit(&metadata.name, &client, visitor)?;
+        let frames = stream.map(|msg| msg.map(PrivateVisitRequest::into_frame));
+        let limits = self.limits.client(&client);
+
+        // Create a stream that connects both ends of the connections together
+        let output = async_stream::stream! {
+            let _connection = connection;
+
+            // The first message needs to contain metadata
+            yield Ok(PrivateVisitResponse {
+                response: Some(private_visit_response::Response::Metadata(
+                    PrivateVisitResponseMetadata { owner },
+                )),
+            });
+
+            for await frame in relay(conn, Vec::new(), frames, limits) {
+                yield frame.map(PrivateVisitResponse::from);
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::VisitPrivateStream))
     }
 }
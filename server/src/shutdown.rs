@@ -0,0 +1,59 @@
+use tokio::sync::watch;
+use tonic::Status;
+
+// Lets every part of the server know that it's shutting down
+//
+// once triggered, the bindings stop accepting visitors and end their streams,
+// while the connections that were already relayed are left to drain
+pub struct Shutdown {
+    triggered: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            triggered: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    // Waits until the shutdown is triggered, resolves right away if it already was
+    pub async fn wait(&self) {
+        let mut triggered = self.triggered.subscribe();
+        // the sender lives as long as we do, so the channel can't be closed
+        let _ = triggered.wait_for(|triggered| *triggered).await;
+    }
+
+    // Waits until the shutdown is triggered, and returns the error that ends a binding's stream,
+    // it lets the client know that it should bind somewhere else
+    pub async fn unbind(&self) -> Status {
+        self.wait().await;
+        Status::unavailable("the server is shutting down")
+    }
+}
+
+// Waits for SIGTERM or SIGINT
+#[cfg(unix)]
+pub async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+
+    Ok(())
+}
+
+// Waits for ctrl-c
+#[cfg(not(unix))]
+pub async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
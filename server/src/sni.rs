@@ -0,0 +1,273 @@
+use std::io::{Error, ErrorKind};
+
+use crate::visitor::Visitor;
+
+// The largest ClientHello we're willing to buffer while looking for the server name
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+const RECORD_HEADER_SIZE: usize = 5;
+const HANDSHAKE_HEADER_SIZE: usize = 4;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_TYPE_HOSTNAME: u8 = 0;
+
+// Reads the tls records that carry the visitor's ClientHello message
+//
+// the raw records are kept in the visitor's prefix, so they can be forwarded untouched
+pub async fn read_client_hello(visitor: &mut Visitor) -> std::io::Result<()> {
+    // a ClientHello might be fragmented over multiple records
+    while !is_complete(&handshake_payload(&visitor.prefix)) {
+        if visitor.prefix.len() > MAX_CLIENT_HELLO_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "client hello is too large",
+            ));
+        }
+        if visitor
+            .prefix
+            .first()
+            .is_some_and(|content_type| *content_type != CONTENT_TYPE_HANDSHAKE)
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "not a tls handshake"));
+        }
+
+        visitor.read_more().await?;
+    }
+
+    Ok(())
+}
+
+// Extracts the server name from the tls records that carry a ClientHello
+//
+// the name is returned lowercased
+pub fn parse_sni(records: &[u8]) -> Option<String> {
+    let handshake = handshake_payload(records);
+    let mut reader = Reader(&handshake);
+
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    let length = reader.u24()?;
+    let mut hello = Reader(reader.take(length)?);
+
+    // version + random
+    hello.take(2 + 32)?;
+    // session id
+    let length = hello.u8()? as usize;
+    hello.take(length)?;
+    // cipher suites
+    let length = hello.u16()? as usize;
+    hello.take(length)?;
+    // compression methods
+    let length = hello.u8()? as usize;
+    hello.take(length)?;
+
+    let length = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(length)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let extension = extensions.take(length)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut extension = Reader(extension);
+        let length = extension.u16()? as usize;
+        let mut names = Reader(extension.take(length)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            if name_type == SERVER_NAME_TYPE_HOSTNAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(name.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+// Joins the payloads of all the (complete) handshake records together
+fn handshake_payload(mut records: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::new();
+
+    while records.len() >= RECORD_HEADER_SIZE && records[0] == CONTENT_TYPE_HANDSHAKE {
+        let length = u16::from_be_bytes([records[3], records[4]]) as usize;
+        let Some(fragment) = records.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length) else {
+            break;
+        };
+
+        payload.extend_from_slice(fragment);
+        records = &records[RECORD_HEADER_SIZE + length..];
+    }
+
+    payload
+}
+
+// Checks whether the payload holds an entire handshake message
+fn is_complete(handshake: &[u8]) -> bool {
+    let mut reader = Reader(handshake);
+    match (reader.u8(), reader.u24()) {
+        (Some(_), Some(length)) => handshake.len() >= HANDSHAKE_HEADER_SIZE + length,
+        _ => false,
+    }
+}
+
+// A minimal big-endian reader over a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|data| data[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|data| u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|data| u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    // A ClientHello for "Example.COM" that was captured from `openssl s_client`
+    const CLIENT_HELLO: &[u8] = &[
+        0x16, 0x03, 0x01, 0x00, 0xda, 0x01, 0x00, 0x00, 0xd6, 0x03, 0x03, 0xa3, 0xd0, 0xa4, 0x7c,
+        0xc5, 0x93, 0x55, 0xa0, 0x16, 0xf2, 0xd7, 0x6c, 0x87, 0xf3, 0x58, 0x6d, 0x8f, 0x59, 0x91,
+        0xa1, 0xdd, 0xa8, 0x38, 0x13, 0xbe, 0xd8, 0x39, 0xaa, 0xb7, 0x74, 0xda, 0x96, 0x00, 0x00,
+        0x36, 0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b,
+        0xc0, 0x2f, 0x00, 0x9e, 0xc0, 0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00,
+        0x67, 0xc0, 0x0a, 0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09, 0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d,
+        0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x01, 0x00, 0x00, 0x77, 0xff,
+        0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x45, 0x78,
+        0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x43, 0x4f, 0x4d, 0x00, 0x0b, 0x00, 0x04, 0x03, 0x00,
+        0x01, 0x02, 0x00, 0x0a, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x1e, 0x00,
+        0x18, 0x00, 0x19, 0x00, 0x23, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00,
+        0x00, 0x0d, 0x00, 0x36, 0x00, 0x34, 0x09, 0x05, 0x09, 0x06, 0x09, 0x04, 0x04, 0x03, 0x05,
+        0x03, 0x06, 0x03, 0x08, 0x07, 0x08, 0x08, 0x08, 0x1a, 0x08, 0x1b, 0x08, 0x1c, 0x08, 0x09,
+        0x08, 0x0a, 0x08, 0x0b, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x04, 0x01, 0x05, 0x01, 0x06,
+        0x01, 0x03, 0x03, 0x03, 0x01, 0x03, 0x02, 0x04, 0x02, 0x05, 0x02, 0x06, 0x02,
+    ];
+    // Where the type of the server name extension is in the captured ClientHello
+    const SERVER_NAME_TYPE_OFFSET: usize = 109;
+
+    // Wraps a handshake payload in a single record
+    fn record(handshake: &[u8]) -> Vec<u8> {
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(handshake);
+        record
+    }
+
+    #[test]
+    fn parses_a_captured_client_hello() {
+        assert_eq!(parse_sni(CLIENT_HELLO).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn parses_a_client_hello_fragmented_over_records() {
+        let handshake = &CLIENT_HELLO[RECORD_HEADER_SIZE..];
+        let (first, second) = handshake.split_at(50);
+        let records = [record(first), record(second)].concat();
+
+        assert!(!is_complete(&handshake_payload(
+            &records[..records.len() - 1]
+        )));
+        assert!(is_complete(&handshake_payload(&records)));
+        assert_eq!(parse_sni(&records).as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn reads_a_client_hello_split_across_reads() {
+        let (mut stream, server) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let mut visitor = Visitor::private(server, addr, addr);
+
+        let writer = tokio::spawn(async move {
+            for chunk in CLIENT_HELLO.chunks(60) {
+                stream.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stream
+        });
+        read_client_hello(&mut visitor).await.unwrap();
+        let _stream = writer.await.unwrap();
+
+        assert_eq!(visitor.prefix, CLIENT_HELLO);
+        assert_eq!(parse_sni(&visitor.prefix).as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_that_are_not_tls() {
+        let (mut stream, server) = tokio::io::duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let mut visitor = Visitor::private(server, addr, addr);
+
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let err = read_client_hello(&mut visitor).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ignores_a_client_hello_without_a_server_name() {
+        assert_eq!(
+            CLIENT_HELLO[SERVER_NAME_TYPE_OFFSET..SERVER_NAME_TYPE_OFFSET + 2],
+            EXTENSION_SERVER_NAME.to_be_bytes()
+        );
+
+        // turns the server name extension into an unknown one
+        let mut records = CLIENT_HELLO.to_vec();
+        records[SERVER_NAME_TYPE_OFFSET] = 0xfa;
+        records[SERVER_NAME_TYPE_OFFSET + 1] = 0xfa;
+        assert_eq!(parse_sni(&records), None);
+    }
+
+    #[test]
+    fn ignores_truncated_client_hellos() {
+        let handshake = &CLIENT_HELLO[RECORD_HEADER_SIZE..];
+        for end in 0..CLIENT_HELLO.len() {
+            assert_eq!(parse_sni(&CLIENT_HELLO[..end]), None);
+        }
+        for end in 0..handshake.len() {
+            assert_eq!(parse_sni(&record(&handshake[..end])), None);
+        }
+    }
+
+    #[test]
+    fn survives_lengths_that_overflow() {
+        // every length field (and anything else) claiming more than there is
+        for offset in RECORD_HEADER_SIZE..CLIENT_HELLO.len() {
+            let mut records = CLIENT_HELLO.to_vec();
+            records[offset] = 0xff;
+            parse_sni(&records);
+        }
+
+        let mut records = CLIENT_HELLO.to_vec();
+        records[3..5].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(parse_sni(&records), None);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// The largest payload a single udp datagram can carry
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// Udp is connectionless, every visitor address that sends datagrams
+// to a bound port is tracked as a virtual session, so the client can
+// tell the visitors apart and the server knows where to send the replies.
+#[derive(Debug)]
+pub struct Sessions {
+    ids: HashMap<SocketAddr, u64>,
+    sessions: HashMap<u64, Session>,
+    next_id: u64,
+    // The amount of sessions that may be open at once
+    max_sessions: usize,
+}
+
+#[derive(Debug)]
+struct Session {
+    addr: SocketAddr,
+    last_seen: Instant,
+}
+
+impl Sessions {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            ids: HashMap::new(),
+            sessions: HashMap::new(),
+            next_id: 0,
+            max_sessions,
+        }
+    }
+
+    // Returns the session id of a visitor, opening a new session if necessary
+    //
+    // marks the session as active, None if a new session would be one too many
+    pub fn touch(&mut self, addr: SocketAddr) -> Option<u64> {
+        if !self.ids.contains_key(&addr) && self.sessions.len() >= self.max_sessions {
+            return None;
+        }
+
+        let id = *self.ids.entry(addr).or_insert_with(|| {
+            self.next_id += 1;
+            self.next_id
+        });
+
+        self.sessions
+            .entry(id)
+            .and_modify(|session| session.last_seen = Instant::now())
+            .or_insert_with(|| Session {
+                addr,
+                last_seen: Instant::now(),
+            });
+
+        Some(id)
+    }
+
+    // Returns the visitor address of a session
+    //
+    // marks the session as active
+    pub fn reply_to(&mut self, id: u64) -> Option<SocketAddr> {
+        let session = self.sessions.get_mut(&id)?;
+        session.last_seen = Instant::now();
+
+        Some(session.addr)
+    }
+
+    // Drops all the sessions that have been idle for longer than the timeout
+    //
+    // returns the ids of the dropped sessions
+    pub fn expire(&mut self, timeout: Duration) -> Vec<u64> {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.last_seen.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired.iter() {
+            if let Some(session) = self.sessions.remove(id) {
+                self.ids.remove(&session.addr);
+            }
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn tracks_visitors_as_sessions() {
+        let mut sessions = Sessions::new(8);
+        let first = sessions.touch(addr(1)).unwrap();
+        let second = sessions.touch(addr(2)).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(sessions.touch(addr(1)), Some(first));
+        assert_eq!(sessions.reply_to(second), Some(addr(2)));
+        assert_eq!(sessions.reply_to(second + 1), None);
+    }
+
+    #[test]
+    fn limits_the_amount_of_sessions() {
+        let mut sessions = Sessions::new(2);
+        let first = sessions.touch(addr(1)).unwrap();
+        sessions.touch(addr(2)).unwrap();
+
+        assert_eq!(sessions.touch(addr(3)), None);
+        // the visitors that already have a session keep it
+        assert_eq!(sessions.touch(addr(1)), Some(first));
+
+        // an expired session makes room for a new one
+        assert_eq!(sessions.expire(Duration::ZERO).len(), 2);
+        assert!(sessions.touch(addr(3)).is_some());
+        assert_eq!(sessions.reply_to(first), None);
+    }
+}
@@ -1,6 +1,72 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use rrp::grpc::LoadBalancing;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
 use tonic::Status;
 
+// The amount of connections the OS queues for a listener before we accept them
+const LISTEN_BACKLOG: i32 = 1024;
+
+// How long a listener waits after failing to accept a connection,
+// errors like running out of file descriptors won't go away if we retry right away
+pub const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+#[allow(clippy::result_large_err)]
 pub fn parse_port(port: i32) -> Result<u16, Status> {
     port.try_into()
         .map_err(|_| Status::invalid_argument(format!("invalid port number: {}", port)))
 }
+
+#[allow(clippy::result_large_err)]
+pub fn parse_load_balancing(strategy: i32) -> Result<LoadBalancing, Status> {
+    LoadBalancing::try_from(strategy).map_err(|_| {
+        Status::invalid_argument(format!("unknown load balancing strategy: {}", strategy))
+    })
+}
+
+#[allow(clippy::result_large_err)]
+pub fn parse_ip(ip: &str) -> Result<IpAddr, Status> {
+    ip.parse()
+        .map_err(|_| Status::invalid_argument(format!("invalid ip address: {}", ip)))
+}
+
+// Creates a socket for the address,
+// the unspecified ipv6 address ("::") is dual-stack, so it accepts ipv4 as well
+fn socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket(addr, Type::STREAM, Protocol::TCP)?;
+    // just like tokio's own listeners, so a restarted server can reuse its ports right away
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+// ipv4 peers of dual-stack sockets show up with ipv4-mapped ipv6 addresses ("::ffff:1.2.3.4"),
+// turns them back into plain ipv4 addresses
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
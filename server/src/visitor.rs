@@ -0,0 +1,171 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rrp::{
+    cidr::Cidr,
+    grpc::ConnectionInfo,
+    proxy_protocol::{self, Decoded},
+};
+use tokio::{
+    io::{AsyncReadExt, DuplexStream},
+    net::TcpStream,
+    time::timeout,
+};
+use tonic::Status;
+
+use crate::{config::Config, groups::Load, relay::Connection, utils};
+
+// How long a trusted source has to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A connection that was made to one of the exposed ports (or private services),
+// it waits in a queue until the client accepts it
+pub struct Visitor {
+    pub stream: Box<dyn Connection>,
+
+    // Bytes that were already read from the stream while routing it,
+    // they need to be forwarded before anything else
+    pub prefix: Vec<u8>,
+
+    pub peer_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    pub accepted_at: SystemTime,
+
+    // Counts the connection towards the load of the binding it was routed to,
+    // for as long as it's held
+    pub load: Option<Load>,
+}
+
+impl Visitor {
+    // Wraps a connection that was just accepted
+    pub fn new(stream: TcpStream, peer_addr: SocketAddr) -> std::io::Result<Self> {
+        Ok(Self {
+            local_addr: utils::canonical_addr(stream.local_addr()?),
+            stream: Box::new(stream),
+            prefix: Vec::new(),
+            peer_addr: utils::canonical_addr(peer_addr),
+            accepted_at: SystemTime::now(),
+            load: None,
+        })
+    }
+
+    // Wraps the server's end of a private tunnel,
+    // the addresses are the ones of the visiting client's connection to the server
+    pub fn private(stream: DuplexStream, peer_addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        Self {
+            stream: Box::new(stream),
+            prefix: Vec::new(),
+            peer_addr,
+            local_addr,
+            accepted_at: SystemTime::now(),
+            load: None,
+        }
+    }
+
+    // Wraps a connection that was just accepted on one of the public listeners
+    //
+    // connections from trusted load balancers start with a PROXY protocol header,
+    // the addresses it holds replace the addresses of the connection itself
+    pub async fn accept(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        config: &Config,
+    ) -> std::io::Result<Self> {
+        let mut visitor = Self::new(stream, peer_addr)?;
+        if config.expects_proxy_protocol(visitor.peer_addr.ip()) {
+            timeout(PROXY_HEADER_TIMEOUT, visitor.read_proxy_header()).await??;
+        }
+
+        // the visitor's real address is only known once the header was read
+        if config.denies_visitor(visitor.peer_addr.ip()) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("visitors from {} are denied", visitor.peer_addr.ip()),
+            ));
+        }
+
+        Ok(visitor)
+    }
+
+    // Reads more data from the visitor into the prefix
+    pub async fn read_more(&mut self) -> std::io::Result<()> {
+        let mut data = [0u8; 1024];
+        let rcount = self.stream.read(&mut data).await?;
+        if rcount == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        self.prefix.extend_from_slice(&data[..rcount]);
+        Ok(())
+    }
+
+    async fn read_proxy_header(&mut self) -> std::io::Result<()> {
+        loop {
+            let decoded = proxy_protocol::decode(&self.prefix)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+            match decoded {
+                Decoded::Incomplete => self.read_more().await?,
+                Decoded::Complete { addresses, length } => {
+                    if let Some((source, destination)) = addresses {
+                        self.peer_addr = source;
+                        self.local_addr = destination;
+                    }
+
+                    // whatever comes after the header belongs to the visitor
+                    self.prefix.drain(..length);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // The details about this connection that are shared with the client
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: self.peer_addr.to_string(),
+            local_addr: self.local_addr.to_string(),
+            accepted_at: self
+                .accepted_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+}
+
+// The visitors a client lets through to one of its bindings
+//
+// a visitor needs to be in one of the allowed ranges (if there are any),
+// and not in any of the denied ones
+#[derive(Debug, Default)]
+pub struct VisitorFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl VisitorFilter {
+    #[allow(clippy::result_large_err)]
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self, Status> {
+        let parse = |ranges: &[String]| {
+            ranges
+                .iter()
+                .map(|range| range.parse::<Cidr>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| Status::invalid_argument(err.to_string()))
+        };
+
+        Ok(Self {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+            && !self.deny.iter().any(|range| range.contains(ip))
+    }
+}